@@ -0,0 +1,379 @@
+use nalgebra::{DMatrix, DVector};
+use wasm_bindgen::prelude::*;
+
+use crate::effects::Effect;
+use crate::field::{
+    Cell, ExitKind, Subway, DIRECTIONS, PROPAGATION_EPSILON, PROPAGATION_MAX_STEPS,
+};
+
+/// Arrival time statistics for a single exit
+#[wasm_bindgen]
+#[derive(Clone, Copy)]
+pub struct ExitTiming {
+    /// exit cell index
+    pub idx: usize,
+    /// exit identity
+    pub kind: ExitKind,
+    /// probability of leaving through this exit
+    pub probability: f64,
+    /// expected step of arrival, for groups leaving through this exit
+    pub expected: f64,
+    /// variance of the arrival step
+    pub variance: f64,
+    /// median step of arrival
+    pub median: u32,
+}
+
+impl ExitTiming {
+    /// Calculate statistics from arrivals per step (starting with step 1)
+    fn from_arrivals(idx: usize, kind: ExitKind, arrivals: &[f64]) -> Self {
+        let probability: f64 = arrivals.iter().sum();
+        let mut timing = ExitTiming {
+            idx,
+            kind,
+            probability,
+            expected: 0.,
+            variance: 0.,
+            median: 0,
+        };
+        if probability <= 0. {
+            return timing;
+        }
+        let mut moment_2 = 0.;
+        let mut cumulative = 0.;
+        for (step_idx, &prob) in arrivals.iter().enumerate() {
+            let step_number = (step_idx + 1) as f64;
+            timing.expected += prob * step_number;
+            moment_2 += prob * step_number * step_number;
+            cumulative += prob;
+            if timing.median == 0 && cumulative >= probability / 2. {
+                timing.median = step_idx as u32 + 1;
+            }
+        }
+        timing.expected /= probability;
+        timing.variance = (moment_2 / probability - timing.expected * timing.expected).max(0.);
+        timing
+    }
+}
+
+/// Mover state index: cell index and heading, linearized
+fn state_idx(idx: usize, heading: usize) -> usize {
+    idx * 4 + heading
+}
+
+#[wasm_bindgen]
+impl Subway {
+    /// Calculate limiting probabilities of leaving the field through each cell
+    ///
+    /// Movement rules depend on the move count only for a few first moves
+    /// (see `MovementModel::stationary_move`), so these are propagated directly,
+    /// and the rest is solved as an absorbing Markov chain over (cell, heading) states.
+    ///
+    /// Returns a probability for every cell, non-zero only for exits
+    /// and the entrance. Probability of a group never leaving the field
+    /// (e.g. circling in a loop without exits, or stopped by a mark effect)
+    /// is not assigned to any cell.
+    pub fn solve_absorption(&self) -> Vec<f64> {
+        let flat_size = self.field.len();
+        let mut absorbed = vec![0.; flat_size];
+        let stationary_move = self.model.stationary_move();
+
+        // step-dependent part: exits collect their visitors directly
+        let mut scratch = self.scratch();
+        for step_number in 1..stationary_move {
+            scratch.step(step_number);
+        }
+        for (idx, prob) in absorbed.iter_mut().enumerate() {
+            if self.field[idx] == Cell::Exit {
+                *prob = scratch.visited[idx];
+            }
+        }
+
+        // stationary part: movers already standing at exits leave right away,
+        // delayed ones are going to leave their cells as they entered them
+        let mut initial = vec![0.; 4 * flat_size];
+        for idx in 0..flat_size {
+            for heading in 0..4 {
+                let mover_prob = scratch.movers[(heading, idx)]
+                    + scratch
+                        .delayed
+                        .iter()
+                        .map(|delayed| delayed[(heading, idx)])
+                        .sum::<f64>();
+                if scratch.is_exit(idx, stationary_move) {
+                    absorbed[idx] += mover_prob;
+                } else {
+                    initial[state_idx(idx, heading)] = mover_prob;
+                }
+            }
+        }
+
+        // transitions between transient states and into exits
+        let mut transitions: Vec<Vec<(usize, f64)>> = vec![Vec::new(); 4 * flat_size];
+        for idx in 0..flat_size {
+            if self.field[idx] == Cell::Wall || self.is_exit(idx, stationary_move) {
+                continue;
+            }
+            for d in DIRECTIONS {
+                // mark effects act on entering groups, delays only hold them back
+                let (heading, kept) = match self.cell_effect(idx) {
+                    Effect::Absorb(probability) => (d, 1. - probability),
+                    Effect::ForceTurn(turn) => (DIRECTIONS[(d as usize + turn as usize) % 4], 1.),
+                    Effect::None | Effect::Delay(_) => (d, 1.),
+                };
+                transitions[state_idx(idx, d as usize)] = self
+                    .mover_transitions(idx, heading, stationary_move)
+                    .map(|(next_idx, next_dir, prob)| (state_idx(next_idx, next_dir), prob * kept))
+                    .collect();
+            }
+        }
+        let leaves = |state: usize| self.is_exit(state / 4, stationary_move);
+
+        // Only states that can still leave the field take part in the equation:
+        // the rest would make the system singular, and holds the mass forever anyway
+        let mut can_leave = vec![false; 4 * flat_size];
+        loop {
+            let mut changed = false;
+            for state in 0..4 * flat_size {
+                if !can_leave[state]
+                    && transitions[state]
+                        .iter()
+                        .any(|&(next, _)| leaves(next) || can_leave[next])
+                {
+                    can_leave[state] = true;
+                    changed = true;
+                }
+            }
+            if !changed {
+                break;
+            }
+        }
+        let transient: Vec<usize> = (0..4 * flat_size).filter(|&s| can_leave[s]).collect();
+        let mut position = vec![usize::MAX; 4 * flat_size];
+        for (pos, &state) in transient.iter().enumerate() {
+            position[state] = pos;
+        }
+
+        // Expected visit counts `y` of transient states satisfy (I - Q^T) y = m,
+        // with Q being transient-to-transient transitions and `m` initial movers
+        let size = transient.len();
+        let mut system = DMatrix::<f64>::identity(size, size);
+        let mut rhs = DVector::<f64>::zeros(size);
+        for (pos, &state) in transient.iter().enumerate() {
+            rhs[pos] = initial[state];
+            for &(next, prob) in &transitions[state] {
+                if can_leave[next] {
+                    system[(position[next], pos)] -= prob;
+                }
+            }
+        }
+        let visits = match system.lu().solve(&rhs) {
+            Some(visits) => visits,
+            None => return absorbed,
+        };
+
+        for (pos, &state) in transient.iter().enumerate() {
+            for &(next, prob) in &transitions[state] {
+                if leaves(next) {
+                    absorbed[next / 4] += visits[pos] * prob;
+                }
+            }
+        }
+        absorbed
+    }
+
+    /// Calculate arrival time statistics for each exit (including the entrance)
+    ///
+    /// Movers are propagated from the initial state until nearly all of the group
+    /// has left the field.
+    pub fn exit_timings(&self) -> Vec<ExitTiming> {
+        let mut scratch = self.scratch();
+        scratch.run_until(PROPAGATION_EPSILON, PROPAGATION_MAX_STEPS);
+
+        scratch
+            .arrivals
+            .iter()
+            .map(|(idx, arrivals)| {
+                let kind = self.get_exit_kind(*idx).unwrap_or(ExitKind::Extra);
+                ExitTiming::from_arrivals(*idx, kind, arrivals)
+            })
+            .collect()
+    }
+
+    /// Calculate limiting probability of leaving the field through exits of `kind`
+    pub fn solve_exit_probability(&self, kind: ExitKind) -> f64 {
+        self.solve_absorption()
+            .iter()
+            .enumerate()
+            .filter(|&(idx, _)| self.get_exit_kind(idx) == Some(kind))
+            .map(|(_, prob)| prob)
+            .sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::effects::{MarkEffect, Turn};
+    use crate::field::Mark;
+    use crate::movement::DungeonModifiers;
+    use wasm_bindgen_test::*;
+
+    fn assert_close(a: f64, b: f64) {
+        assert!((a - b).abs() < 1e-9, "{} != {}", a, b);
+    }
+
+    #[wasm_bindgen_test]
+    fn test_absorption_freeway() {
+        let mut subway = Subway::new();
+        subway.set_field(128, Cell::Entrance);
+        subway.set_field(127, Cell::Pass);
+        subway.set_field(126, Cell::Pass);
+        subway.set_field(125, Cell::Exit);
+        subway.init(&DungeonModifiers::default());
+
+        let absorbed = subway.solve_absorption();
+        assert_close(absorbed[125], 1.);
+        assert_close(absorbed.iter().sum(), 1.);
+    }
+
+    #[wasm_bindgen_test]
+    fn test_absorption_dead_end() {
+        // nowhere to go but back to the entrance
+        let mut subway = Subway::new();
+        subway.set_field(128, Cell::Entrance);
+        subway.set_field(127, Cell::Pass);
+        subway.set_field(126, Cell::Pass);
+        subway.init(&DungeonModifiers::default());
+
+        let absorbed = subway.solve_absorption();
+        assert_close(absorbed[128], 1.);
+    }
+
+    #[wasm_bindgen_test]
+    fn test_exit_timings() {
+        let mut subway = Subway::new();
+        subway.set_field(86, Cell::Pass);
+        subway.set_field(87, Cell::Pass);
+        subway.set_field(88, Cell::Pass);
+        subway.set_field(106, Cell::Pass);
+        subway.set_field(108, Cell::Pass);
+        subway.set_field(126, Cell::Pass);
+        subway.set_field(127, Cell::Exit);
+        subway.set_field(128, Cell::Entrance);
+        subway.init(&DungeonModifiers::default());
+
+        // half of the group exits at once, the other half walks around
+        let timings = subway.exit_timings();
+        assert_eq!(timings.len(), 2);
+        let exit = timings.iter().find(|t| t.idx == 127).unwrap();
+        assert_close(exit.probability, 1.);
+        assert_close(exit.expected, 4.);
+        assert_close(exit.variance, 9.);
+        assert_eq!(exit.median, 1);
+
+        let entrance = timings.iter().find(|t| t.idx == 128).unwrap();
+        assert_close(entrance.probability, 0.);
+    }
+
+    #[wasm_bindgen_test]
+    fn test_exit_kinds() {
+        // fork right after the entrance, with a different exit on each side
+        let mut subway = Subway::new();
+        subway.set_field(128, Cell::Entrance);
+        subway.set_field(127, Cell::Pass);
+        assert!(subway.set_exit(126, ExitKind::Treasury));
+        subway.set_field(129, Cell::Pass);
+        assert!(subway.set_exit(130, ExitKind::Subtreasury));
+        assert!(!subway.set_exit(125, ExitKind::Entrance));
+        assert!(!subway.set_exit(0, ExitKind::Treasury));
+        subway.init(&DungeonModifiers::default());
+        subway.run_until(1e-12, 1000);
+
+        assert!(subway.get_exit_kind(126) == Some(ExitKind::Treasury));
+        assert!(subway.get_exit_kind(128) == Some(ExitKind::Entrance));
+        assert!(subway.get_exit_kind(127).is_none());
+        let treasury = subway.get_exit_probability(ExitKind::Treasury);
+        let subtreasury = subway.get_exit_probability(ExitKind::Subtreasury);
+        assert!(treasury > 0. && subtreasury > 0.);
+        assert_close(treasury, subway.solve_exit_probability(ExitKind::Treasury));
+        assert_close(
+            subtreasury,
+            subway.solve_exit_probability(ExitKind::Subtreasury),
+        );
+        assert_close(subway.get_exit_probability(ExitKind::Extra), 0.);
+
+        let timings = subway.exit_timings();
+        let exit = timings.iter().find(|t| t.idx == 130).unwrap();
+        assert!(exit.kind == ExitKind::Subtreasury);
+
+        // clearing the mark makes an extra exit, and the results follow
+        assert!(subway.set_mark(126, Mark::None));
+        assert!(subway.get_exit_kind(126) == Some(ExitKind::Extra));
+        assert_close(subway.get_exit_probability(ExitKind::Treasury), 0.);
+        assert_close(subway.get_exit_probability(ExitKind::Extra), treasury);
+        subway.set_mark(126, Mark::Treasury);
+        assert!(subway.get_exit_kind(126) == Some(ExitKind::Treasury));
+
+        // redrawing the cell forgets its identity
+        subway.set_field(130, Cell::Exit);
+        assert!(subway.get_exit_kind(130) == Some(ExitKind::Extra));
+    }
+
+    #[wasm_bindgen_test]
+    fn test_absorption_matches_steps() {
+        let mut subway = Subway::new();
+        for idx in [86, 87, 88, 89, 106, 108, 109, 126, 127, 129, 146, 147, 148, 149] {
+            subway.set_field(idx, Cell::Pass);
+        }
+        subway.set_field(128, Cell::Entrance);
+        subway.set_field(66, Cell::Exit);
+        subway.set_field(150, Cell::Exit);
+        subway.init(&DungeonModifiers {
+            jumpy: true,
+            ..Default::default()
+        });
+
+        let absorbed = subway.solve_absorption();
+        let mut early = subway.clone();
+        for step_number in 1..subway.model.entrance_exit_move() {
+            early.step(step_number);
+        }
+        for step_number in 1..2000 {
+            subway.step(step_number);
+        }
+        assert_close(absorbed[66], subway.visited[66]);
+        assert_close(absorbed[150], subway.visited[150]);
+        // entrance visitors only leave once it works as an exit
+        assert_close(absorbed[128], subway.visited[128] - early.visited[128]);
+        assert_close(absorbed.iter().sum(), 1.);
+    }
+
+    #[wasm_bindgen_test]
+    fn test_absorption_with_effects() {
+        let mut subway = Subway::new();
+        for idx in [86, 87, 88, 89, 106, 108, 109, 126, 127, 129, 146, 147, 148, 149] {
+            subway.set_field(idx, Cell::Pass);
+        }
+        subway.set_field(128, Cell::Entrance);
+        subway.set_field(66, Cell::Exit);
+        subway.set_field(150, Cell::Exit);
+        subway.set_mark(87, Mark::FinalBoss);
+        subway.set_mark_effect(Mark::FinalBoss, MarkEffect::absorb(0.3));
+        subway.set_mark(109, Mark::Trap);
+        subway.set_mark_effect(Mark::Trap, MarkEffect::force_turn(Turn::Back));
+        subway.set_mark(147, Mark::Luck);
+        subway.set_mark_effect(Mark::Luck, MarkEffect::delay(3));
+        subway.init(&DungeonModifiers {
+            jumpy: true,
+            ..Default::default()
+        });
+
+        let absorbed = subway.solve_absorption();
+        subway.run_until(1e-12, 2000);
+        for idx in [66, 128, 150] {
+            assert_close(absorbed[idx], subway.get_arrivals(idx).iter().sum());
+        }
+        assert_close(absorbed.iter().sum(), 1. - subway.get_stopped_mass());
+    }
+}
@@ -16,7 +16,7 @@ pub struct Brief {
 
 impl Brief {
     pub fn distance(&self, other: &Self) -> usize {
-        (self.b.clone() ^ other.b.clone()).count_ones()
+        (self.b ^ other.b).count_ones()
     }
 }
 
@@ -0,0 +1,525 @@
+use std::collections::VecDeque;
+
+use nalgebra::Vector4;
+use wasm_bindgen::prelude::*;
+
+use crate::effects::{Effect, Turn};
+use crate::field::{Cell, ExitKind, Mark, RunSummary, Subway};
+use crate::ledger::LedgerEntry;
+use crate::movement::DungeonModifiers;
+
+/// Cell of a dungeon: floor number and cell index on the floor
+type FloorCell = (usize, usize);
+
+/// Groups that climbed a ladder, on the linked cell
+#[derive(Clone)]
+struct Landing {
+    /// linked cell the groups climbed to
+    cell: FloorCell,
+    /// movers of the groups
+    movers: Vector4<f64>,
+    /// movers that have not visited tracked cells yet, by target
+    pending: Vec<Vector4<f64>>,
+}
+
+impl Landing {
+    /// Turn all the groups by `turn`
+    fn turn(&mut self, turn: Turn) {
+        let turned = |movers: &Vector4<f64>| {
+            Vector4::from_fn(|heading, _| movers[(heading + 4 - turn as usize) % 4])
+        };
+        self.movers = turned(&self.movers);
+        for pending in self.pending.iter_mut() {
+            *pending = turned(pending);
+        }
+    }
+
+    /// Keep `1 - probability` of all the groups, returning the stopped mass
+    fn absorb(&mut self, probability: f64) -> f64 {
+        let stopped = self.movers.sum() * probability;
+        self.movers *= 1. - probability;
+        for pending in self.pending.iter_mut() {
+            *pending *= 1. - probability;
+        }
+        stopped
+    }
+}
+
+/// Dungeon of several floors, connected by ladders
+///
+/// Groups stepping on a ladder cell climb to the linked cell on another floor
+/// at once, keeping their heading, and move on from there. Effects of marks
+/// of the linked cells apply to them, and they leave the linked cells
+/// before they can climb again. All floors move with the modifiers given to `init`.
+#[wasm_bindgen]
+#[derive(Clone, Default)]
+pub struct Dungeon {
+    floors: Vec<Subway>,
+    /// linked ladder cells, groups climb both ways
+    ladders: Vec<(FloorCell, FloorCell)>,
+    last_step: u32,
+    /// groups waiting on linked ladder cells they climbed to, by moves left to wait
+    landings: VecDeque<Vec<Landing>>,
+    /// track first visits of cells from the next `init`
+    first_visit_tracking: bool,
+}
+
+#[wasm_bindgen]
+impl Dungeon {
+    #[wasm_bindgen(constructor)]
+    pub fn new() -> Self {
+        Dungeon::default()
+    }
+
+    /// Add a floor, returning its number
+    ///
+    /// The group starts at the entrance, which must be on one floor only.
+    /// Returns nothing if both the floor and one of the other floors have an entrance.
+    pub fn add_floor(&mut self, floor: Subway) -> Option<usize> {
+        let has_entrance = |floor: &Subway| floor.field.contains(&Cell::Entrance);
+        if has_entrance(&floor) && self.floors.iter().any(has_entrance) {
+            return None;
+        }
+        self.floors.push(floor);
+        Some(self.floors.len() - 1)
+    }
+
+    /// Get number of floors
+    pub fn get_floor_count(&self) -> usize {
+        self.floors.len()
+    }
+
+    /// Get a copy of a floor, with its simulation state
+    pub fn get_floor(&self, floor: usize) -> Option<Subway> {
+        self.floors.get(floor).cloned()
+    }
+
+    /// Link ladder cell `idx` of `floor` with ladder cell `other_idx` of `other_floor`
+    ///
+    /// Both cells must be passable and marked with `Mark::Ladder`, on different floors.
+    /// Earlier links of either cell are replaced. Returns false if cells can't be linked.
+    pub fn link_ladder(
+        &mut self,
+        floor: usize,
+        idx: usize,
+        other_floor: usize,
+        other_idx: usize,
+    ) -> bool {
+        let is_ladder = |(floor, idx): FloorCell| {
+            self.floors.get(floor).is_some_and(|subway| {
+                idx < subway.get_flat_size()
+                    && subway.get_field(idx) == Cell::Pass
+                    && subway.get_mark(idx) == Mark::Ladder
+            })
+        };
+        let (one, other) = ((floor, idx), (other_floor, other_idx));
+        if floor == other_floor || !is_ladder(one) || !is_ladder(other) {
+            return false;
+        }
+        self.ladders
+            .retain(|&(a, b)| ![one, other].contains(&a) && ![one, other].contains(&b));
+        self.ladders.push((one, other));
+        true
+    }
+
+    /// Get ladder cell linked with cell `idx` of `floor`, as floor number and cell index
+    pub fn get_ladder_link(&self, floor: usize, idx: usize) -> Vec<usize> {
+        self.linked_cell((floor, idx))
+            .map_or(Vec::new(), |(floor, idx)| vec![floor, idx])
+    }
+
+    /// Initialize all floors for the first step
+    pub fn init(&mut self, modifiers: &DungeonModifiers) {
+        for floor in self.floors.iter_mut() {
+            floor.init(modifiers);
+            floor.first_visits = None;
+        }
+        if self.first_visit_tracking {
+            // every floor follows groups that have not visited cells of any floor
+            let targets: Vec<FloorCell> = self
+                .floors
+                .iter()
+                .enumerate()
+                .flat_map(|(floor, subway)| subway.first_visit_cells().map(move |idx| (floor, idx)))
+                .collect();
+            for (number, floor) in self.floors.iter_mut().enumerate() {
+                let cells = targets
+                    .iter()
+                    .map(|&(floor, idx)| (floor == number).then_some(idx))
+                    .collect();
+                floor.start_first_visits(cells);
+            }
+        }
+        self.landings.clear();
+        self.last_step = 0;
+    }
+
+    /// Perform a mover step on all floors
+    pub fn step(&mut self, step_number: u32) {
+        let moves_per_step = match self.floors.first() {
+            Some(floor) => floor.modifiers.moves_per_step(),
+            None => return,
+        };
+        let mut entries: Vec<LedgerEntry> = self
+            .floors
+            .iter_mut()
+            .map(|floor| floor.begin_step(step_number))
+            .collect();
+        for _ in 0..moves_per_step {
+            for (floor, entry) in self.floors.iter_mut().zip(entries.iter_mut()) {
+                floor.arrive(step_number, entry);
+            }
+            self.climb_ladders(&mut entries);
+            for (floor, entry) in self.floors.iter_mut().zip(entries.iter_mut()) {
+                floor.depart(step_number, entry);
+            }
+        }
+        for (floor, entry) in self.floors.iter_mut().zip(entries) {
+            floor.finish_step(entry);
+        }
+        self.last_step = step_number;
+    }
+
+    /// Perform steps until movers mass of all floors falls below `epsilon`,
+    /// or `max_steps` steps are done
+    pub fn run_until(&mut self, epsilon: f64, max_steps: u32) -> RunSummary {
+        let mut steps = 0;
+        while steps < max_steps && self.in_flight_mass() >= epsilon {
+            self.step(self.last_step + 1);
+            steps += 1;
+        }
+        let remaining = self.in_flight_mass();
+        RunSummary {
+            steps,
+            last_step: self.last_step,
+            remaining,
+            converged: remaining < epsilon,
+        }
+    }
+
+    /// Get number of the last performed step
+    pub fn get_last_step(&self) -> u32 {
+        self.last_step
+    }
+
+    /// Get visiting probabilities for all cells of a floor
+    pub fn get_visited_probabilities(&self, floor: usize) -> Vec<f64> {
+        self.floors
+            .get(floor)
+            .map_or(Vec::new(), Subway::get_visited_probabilities)
+    }
+
+    /// Turn tracking of first visits of cells on or off, starting with the next `init`
+    ///
+    /// See `Subway::set_first_visit_tracking`, tracking of all floors is set here.
+    pub fn set_first_visit_tracking(&mut self, enabled: bool) {
+        self.first_visit_tracking = enabled;
+    }
+
+    /// Get probabilities of visiting each cell of a floor at least once
+    ///
+    /// The result is empty unless tracking was turned on before `init`.
+    pub fn get_first_visit_probabilities(&self, floor: usize) -> Vec<f64> {
+        self.floors
+            .get(floor)
+            .map_or(Vec::new(), Subway::get_first_visit_probabilities)
+    }
+
+    /// Get probability of leaving the dungeon through exits of `kind`, on any floor
+    pub fn get_exit_probability(&self, kind: ExitKind) -> f64 {
+        self.floors
+            .iter()
+            .map(|floor| floor.get_exit_probability(kind))
+            .sum()
+    }
+
+    /// Get probability of the group leaving the dungeon through its entrance
+    pub fn get_entrance_exit_probability(&self) -> f64 {
+        self.floors
+            .iter()
+            .map(Subway::get_entrance_exit_probability)
+            .sum()
+    }
+}
+
+impl Dungeon {
+    /// Ladder cell linked with `cell`
+    fn linked_cell(&self, cell: FloorCell) -> Option<FloorCell> {
+        self.ladders.iter().find_map(|&(a, b)| {
+            if a == cell {
+                Some(b)
+            } else if b == cell {
+                Some(a)
+            } else {
+                None
+            }
+        })
+    }
+
+    /// Movers mass inside all floors, including groups waiting on ladders
+    fn in_flight_mass(&self) -> f64 {
+        let waiting: f64 = self
+            .landings
+            .iter()
+            .flatten()
+            .map(|landing| landing.movers.sum())
+            .sum();
+        self.floors.iter().map(Subway::in_flight_mass).sum::<f64>() + waiting
+    }
+
+    /// Take movers in ladder cells to the linked cells, counting them as visitors there
+    ///
+    /// Climbed groups land after all climbs of the move are done, and move on
+    /// before they can climb again, including groups delayed on the linked cells.
+    fn climb_ladders(&mut self, entries: &mut [LedgerEntry]) {
+        let mut climbs: Vec<(FloorCell, FloorCell, Vector4<f64>)> = Vec::new();
+        for &(a, b) in &self.ladders {
+            for (from, to) in [(a, b), (b, a)] {
+                let movers = self.floors[from.0].movers.column(from.1);
+                if movers.iter().any(|&v| v != 0.) {
+                    climbs.push((from, to, Vector4::from_iterator(movers.iter().copied())));
+                }
+            }
+        }
+        let landings: Vec<Landing> = climbs
+            .into_iter()
+            .map(|((from_floor, from_idx), to, movers)| {
+                let mass = movers.sum();
+                self.floors[from_floor].movers.column_mut(from_idx).fill(0.);
+                self.floors[to.0].visited[to.1] += mass;
+                entries[from_floor].transferred += mass;
+                Landing {
+                    cell: to,
+                    movers,
+                    pending: self.climb_first_visits((from_floor, from_idx), to),
+                }
+            })
+            .collect();
+        for landing in landings {
+            self.land(landing, entries);
+        }
+        // groups done waiting move on from the ladder
+        if let Some(released) = self.landings.pop_front() {
+            for landing in released {
+                self.place(landing, entries);
+            }
+        }
+    }
+
+    /// Apply effects of the mark of the linked cell to climbed groups,
+    /// and place them there unless they are delayed
+    fn land(&mut self, mut landing: Landing, entries: &mut [LedgerEntry]) {
+        let (floor, idx) = landing.cell;
+        match self.floors[floor].cell_effect(idx) {
+            Effect::None => (),
+            Effect::Absorb(probability) => {
+                let stopped = landing.absorb(probability);
+                entries[floor].transferred -= stopped;
+                entries[floor].stopped += stopped;
+            }
+            Effect::Delay(steps) => {
+                let moves = (steps * self.floors[floor].modifiers.moves_per_step()) as usize;
+                if self.landings.len() <= moves {
+                    self.landings.resize(moves + 1, Vec::new());
+                }
+                self.landings[moves].push(landing);
+                return;
+            }
+            Effect::ForceTurn(turn) => landing.turn(turn),
+        }
+        self.place(landing, entries);
+    }
+
+    /// Put climbed groups on the linked cell, to move on from there
+    fn place(&mut self, landing: Landing, entries: &mut [LedgerEntry]) {
+        let (floor, idx) = landing.cell;
+        let subway = &mut self.floors[floor];
+        let mut arrived = subway.movers.column_mut(idx);
+        arrived += &landing.movers;
+        entries[floor].transferred -= landing.movers.sum();
+        if let Some(first_visits) = subway.first_visits.as_mut() {
+            for (target, movers) in landing.pending.iter().enumerate() {
+                let mut arrived = first_visits.pending[target].column_mut(idx);
+                arrived += movers;
+            }
+        }
+    }
+
+    /// Take movers that have not visited tracked cells yet from ladder cell `from`,
+    /// counting first visits of the linked cell `to`
+    ///
+    /// Returns the movers still pending after the climb, by target.
+    fn climb_first_visits(&mut self, from: FloorCell, to: FloorCell) -> Vec<Vector4<f64>> {
+        let mut climbed: Vec<Vector4<f64>> = match self.floors[from.0].first_visits.as_mut() {
+            Some(first_visits) => first_visits
+                .pending
+                .iter_mut()
+                .map(|pending| {
+                    let movers = Vector4::from_iterator(pending.column(from.1).iter().copied());
+                    pending.column_mut(from.1).fill(0.);
+                    movers
+                })
+                .collect(),
+            None => return Vec::new(),
+        };
+        if let Some(first_visits) = self.floors[to.0].first_visits.as_mut() {
+            for (target, movers) in climbed.iter_mut().enumerate() {
+                if first_visits.cells[target] == Some(to.1) {
+                    first_visits.visits[target] += movers.sum();
+                    movers.fill(0.);
+                }
+            }
+        }
+        climbed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::effects::MarkEffect;
+    use wasm_bindgen_test::*;
+
+    #[wasm_bindgen_test]
+    fn test_ladders() {
+        // the entrance floor is a dead end with a ladder,
+        // the treasury is down the ladder
+        let mut upper = Subway::new();
+        upper.set_field(128, Cell::Entrance);
+        upper.set_field(127, Cell::Pass);
+        upper.set_field(126, Cell::Pass);
+        upper.set_mark(126, Mark::Ladder);
+        let mut lower = Subway::new();
+        lower.set_field(126, Cell::Pass);
+        lower.set_mark(126, Mark::Ladder);
+        lower.set_field(125, Cell::Pass);
+        lower.set_exit(124, ExitKind::Treasury);
+
+        let mut dungeon = Dungeon::new();
+        assert_eq!(dungeon.add_floor(upper), Some(0));
+        assert_eq!(dungeon.add_floor(lower), Some(1));
+        assert!(!dungeon.link_ladder(0, 127, 1, 126));
+        assert!(!dungeon.link_ladder(0, 126, 0, 126));
+        assert!(dungeon.link_ladder(0, 126, 1, 126));
+        assert_eq!(dungeon.get_ladder_link(1, 126), vec![0, 126]);
+
+        dungeon.init(&DungeonModifiers::default());
+        let summary = dungeon.run_until(1e-12, 100);
+        assert!(summary.converged);
+        assert_eq!(dungeon.get_exit_probability(ExitKind::Treasury), 1.);
+        assert_eq!(dungeon.get_entrance_exit_probability(), 0.);
+
+        // heat maps of both floors show the ladder
+        let upper = dungeon.get_visited_probabilities(0);
+        let lower = dungeon.get_visited_probabilities(1);
+        assert_eq!(upper[126], 1.);
+        assert_eq!(upper[125], 0.);
+        assert_eq!(lower[126], 1.);
+        assert_eq!(lower[125], 1.);
+        assert!(dungeon.get_visited_probabilities(2).is_empty());
+        assert!(dungeon.get_first_visit_probabilities(0).is_empty());
+
+        // first visits are followed up and down the ladder
+        dungeon.set_first_visit_tracking(true);
+        dungeon.init(&DungeonModifiers::default());
+        dungeon.run_until(1e-12, 100);
+        let upper = dungeon.get_first_visit_probabilities(0);
+        let lower = dungeon.get_first_visit_probabilities(1);
+        assert_eq!(upper[128], 1.);
+        assert_eq!(upper[126], 1.);
+        assert_eq!(lower[126], 1.);
+        assert_eq!(lower[124], 1.);
+        assert_eq!(upper[125], 0.);
+
+        // every floor keeps its mass balance
+        for floor in 0..dungeon.get_floor_count() {
+            let ledger = dungeon.get_floor(floor).unwrap().get_ledger();
+            assert!(ledger.iter().all(|entry| entry.is_balanced()));
+        }
+        let climbed: f64 = dungeon
+            .get_floor(0)
+            .unwrap()
+            .get_ledger()
+            .iter()
+            .map(|entry| entry.transferred)
+            .sum();
+        assert_eq!(climbed, 1.);
+    }
+
+    /// Dead end with the entrance and a ladder down to the treasury,
+    /// with `effect` on the lower ladder cell
+    fn ladder_dungeon(effect: MarkEffect) -> Dungeon {
+        let mut upper = Subway::new();
+        upper.set_field(128, Cell::Entrance);
+        upper.set_field(127, Cell::Pass);
+        upper.set_field(126, Cell::Pass);
+        upper.set_mark(126, Mark::Ladder);
+        let mut lower = Subway::new();
+        lower.set_field(126, Cell::Pass);
+        lower.set_mark(126, Mark::Ladder);
+        lower.set_field(125, Cell::Pass);
+        lower.set_exit(124, ExitKind::Treasury);
+        lower.set_mark_effect(Mark::Ladder, effect);
+
+        let mut dungeon = Dungeon::new();
+        dungeon.add_floor(upper);
+        dungeon.add_floor(lower);
+        dungeon.link_ladder(0, 126, 1, 126);
+        dungeon.set_first_visit_tracking(true);
+        dungeon.init(&DungeonModifiers::default());
+        dungeon.run_until(1e-12, 100);
+        dungeon
+    }
+
+    #[wasm_bindgen_test]
+    fn test_ladder_effects() {
+        let balanced = |dungeon: &Dungeon| {
+            (0..dungeon.get_floor_count()).all(|floor| {
+                let ledger = dungeon.get_floor(floor).unwrap().get_ledger();
+                ledger.iter().all(|entry| entry.is_balanced())
+            })
+        };
+        let arrival_step = |dungeon: &Dungeon| {
+            let arrivals = dungeon.get_floor(1).unwrap().get_arrivals(124);
+            arrivals.iter().position(|&mass| mass != 0.)
+        };
+        let plain = ladder_dungeon(MarkEffect::none());
+        assert!(balanced(&plain));
+
+        // groups are stopped on the lower floor
+        let absorbing = ladder_dungeon(MarkEffect::absorb(0.5));
+        assert_eq!(absorbing.get_exit_probability(ExitKind::Treasury), 0.5);
+        assert_eq!(absorbing.get_floor(1).unwrap().get_stopped_mass(), 0.5);
+        assert_eq!(absorbing.get_first_visit_probabilities(1)[124], 0.5);
+        assert!(balanced(&absorbing));
+
+        // groups wait on the lower ladder, then move on without climbing back
+        let delaying = ladder_dungeon(MarkEffect::delay(2));
+        assert_eq!(delaying.get_exit_probability(ExitKind::Treasury), 1.);
+        assert_eq!(delaying.get_visited_probabilities(0)[126], 1.);
+        assert_eq!(delaying.get_visited_probabilities(1)[126], 1.);
+        assert_eq!(delaying.get_first_visit_probabilities(1)[124], 1.);
+        assert_eq!(
+            arrival_step(&delaying),
+            arrival_step(&plain).map(|step| step + 2)
+        );
+        assert!(balanced(&delaying));
+
+        // a single group starts at the only entrance
+        let mut dungeon = Dungeon::new();
+        let mut entrance_floor = Subway::new();
+        entrance_floor.set_field(128, Cell::Entrance);
+        assert_eq!(dungeon.add_floor(Subway::new()), Some(0));
+        assert_eq!(dungeon.add_floor(entrance_floor.clone()), Some(1));
+        assert_eq!(dungeon.add_floor(entrance_floor), None);
+        assert_eq!(dungeon.get_floor_count(), 2);
+
+        // modifiers of floors are set by `init` of the dungeon
+        let mut jumpy = Subway::new();
+        jumpy.init(&DungeonModifiers {
+            jumpy: true,
+            ..Default::default()
+        });
+        assert_eq!(dungeon.add_floor(jumpy), Some(2));
+    }
+}
@@ -0,0 +1,246 @@
+use std::collections::VecDeque;
+
+use wasm_bindgen::prelude::*;
+
+use crate::field::{Mark, MoverField, Subway};
+use crate::ledger::LedgerEntry;
+
+/// Number of kinds of marks
+pub(crate) const MARK_COUNT: usize = Mark::Fountain as usize + 1;
+
+/// Turn relative to the heading of a group
+#[wasm_bindgen]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Turn {
+    Right = 1,
+    Back = 2,
+    Left = 3,
+}
+
+/// What happens to a group entering a cell
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub(crate) enum Effect {
+    None,
+    /// the group is stopped with this probability
+    Absorb(f64),
+    /// the group stays in the cell for this many steps
+    Delay(u32),
+    /// the group leaves the cell as if it came turned
+    ForceTurn(Turn),
+}
+
+/// Effect of a mark on groups entering its cells
+#[wasm_bindgen]
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct MarkEffect(pub(crate) Effect);
+
+impl Default for MarkEffect {
+    fn default() -> Self {
+        MarkEffect(Effect::None)
+    }
+}
+
+#[wasm_bindgen]
+impl MarkEffect {
+    /// No effect, cells with the mark are ordinary cells
+    pub fn none() -> Self {
+        MarkEffect(Effect::None)
+    }
+
+    /// Stop the group with `probability` (e.g. a boss defeating it),
+    /// `absorb(1.)` always ends the run
+    pub fn absorb(probability: f64) -> Self {
+        MarkEffect(Effect::Absorb(probability.clamp(0., 1.)))
+    }
+
+    /// Keep the group in the cell for `steps` steps
+    pub fn delay(steps: u32) -> Self {
+        MarkEffect(Effect::Delay(steps))
+    }
+
+    /// Turn the group before it leaves the cell
+    ///
+    /// The group then moves as if it entered the cell with the turned heading.
+    pub fn force_turn(turn: Turn) -> Self {
+        MarkEffect(Effect::ForceTurn(turn))
+    }
+
+    /// Tell if the effect leaves groups alone
+    pub fn is_none(&self) -> bool {
+        self.0 == Effect::None
+    }
+}
+
+#[wasm_bindgen]
+impl Subway {
+    /// Set effect of `mark` on groups entering cells with it
+    ///
+    /// Effects are applied by stepping, and by analyses of the field, except
+    /// for exact fractions (`exact_visited`) and most probable routes (`top_paths`),
+    /// which give no results for fields with effects.
+    pub fn set_mark_effect(&mut self, mark: Mark, effect: MarkEffect) {
+        self.mark_effects[mark as usize] = effect;
+    }
+
+    /// Get effect of `mark`
+    pub fn get_mark_effect(&self, mark: Mark) -> MarkEffect {
+        self.mark_effects[mark as usize]
+    }
+
+    /// Get movers mass waiting in delaying cells
+    pub fn get_delayed_mass(&self) -> f64 {
+        self.delayed.iter().map(|movers| movers.sum()).sum()
+    }
+}
+
+impl Subway {
+    /// Tell if marks on the field have effects
+    pub(crate) fn has_mark_effects(&self) -> bool {
+        self.marks
+            .iter()
+            .any(|&mark| !self.mark_effects[mark as usize].is_none())
+    }
+
+    /// Effect of the mark of cell `idx`
+    pub(crate) fn cell_effect(&self, idx: usize) -> Effect {
+        self.mark_effects[self.marks[idx] as usize].0
+    }
+
+    /// Apply effects of marks to the movers in marked cells, before they move on
+    pub(crate) fn apply_mark_effects(&mut self, step_number: u32, entry: &mut LedgerEntry) {
+        let mut movers = std::mem::replace(&mut self.movers, MoverField::zeros(0, 0));
+        let mut delayed = std::mem::take(&mut self.delayed);
+        entry.stopped += self.apply_effects_to(&mut movers, &mut delayed, step_number);
+        self.movers = movers;
+        self.delayed = delayed;
+    }
+
+    /// Apply effects of marks to `movers`, waiting in `delayed` when delayed,
+    /// returning the stopped mass
+    ///
+    /// Groups delayed earlier and done waiting are released afterwards,
+    /// so they are not affected again.
+    pub(crate) fn apply_effects_to(
+        &self,
+        movers: &mut MoverField,
+        delayed: &mut VecDeque<MoverField>,
+        step_number: u32,
+    ) -> f64 {
+        let moves_per_step = self.modifiers.moves_per_step() as usize;
+        let mut stopped = 0.;
+        for idx in 0..self.field.len() {
+            let effect = self.cell_effect(idx);
+            if effect == Effect::None
+                || self.is_exit(idx, step_number)
+                || movers.column(idx).iter().all(|&v| v == 0.)
+            {
+                continue;
+            }
+            let mass = movers.column(idx).sum();
+            match effect {
+                Effect::None => (),
+                Effect::Absorb(probability) => {
+                    stopped += mass * probability;
+                    movers.column_mut(idx).scale_mut(1. - probability);
+                }
+                Effect::Delay(steps) => {
+                    let moves = steps as usize * moves_per_step;
+                    if delayed.len() <= moves {
+                        let cells = self.field.len();
+                        delayed.resize(moves + 1, MoverField::zeros(4, cells));
+                    }
+                    let waiting = movers.column(idx).into_owned();
+                    let mut delayed = delayed[moves].column_mut(idx);
+                    delayed += &waiting;
+                    movers.column_mut(idx).fill(0.);
+                }
+                Effect::ForceTurn(turn) => {
+                    let headings = movers.column(idx).into_owned();
+                    for heading in 0..4 {
+                        movers[((heading + turn as usize) % 4, idx)] = headings[heading];
+                    }
+                }
+            }
+        }
+
+        if let Some(released) = delayed.pop_front() {
+            *movers += released;
+        }
+        stopped
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::field::{Cell, ExitKind};
+    use crate::movement::DungeonModifiers;
+    use crate::snapshot::SubwaySnapshot;
+    use wasm_bindgen_test::*;
+
+    /// Corridor from the entrance past a boss to the treasury
+    fn boss_corridor(effect: MarkEffect) -> Subway {
+        let mut subway = Subway::new();
+        subway.set_field(128, Cell::Entrance);
+        subway.set_field(127, Cell::Pass);
+        subway.set_mark(127, Mark::FinalBoss);
+        subway.set_field(126, Cell::Pass);
+        subway.set_exit(125, ExitKind::Treasury);
+        subway.set_mark_effect(Mark::FinalBoss, effect);
+        subway.init(&DungeonModifiers::default());
+        subway.set_ledger_checks(true);
+        subway
+    }
+
+    #[wasm_bindgen_test]
+    fn test_absorb() {
+        let mut subway = boss_corridor(MarkEffect::absorb(0.4));
+        subway.run_until(1e-12, 100);
+        assert!((subway.get_exit_probability(ExitKind::Treasury) - 0.6).abs() < 1e-12);
+        assert!((subway.get_stopped_mass() - 0.4).abs() < 1e-12);
+        assert!(subway.get_unbalanced_steps().is_empty());
+
+        let mut subway = boss_corridor(MarkEffect::absorb(1.));
+        subway.run_until(1e-12, 100);
+        assert_eq!(subway.get_exit_probability(ExitKind::Treasury), 0.);
+        assert_eq!(subway.get_stopped_mass(), 1.);
+        assert_eq!(subway.get_visited_probability(127), 1.);
+        assert_eq!(subway.get_visited_probability(126), 0.);
+    }
+
+    #[wasm_bindgen_test]
+    fn test_delay() {
+        let mut subway = boss_corridor(MarkEffect::none());
+        assert!(!subway.has_mark_effects());
+        subway.run_until(1e-12, 100);
+        let arrival_step =
+            |subway: &Subway| subway.get_arrivals(125).iter().position(|&mass| mass == 1.);
+        let arrival = arrival_step(&subway).unwrap();
+
+        let mut subway = boss_corridor(MarkEffect::delay(3));
+        subway.step(1);
+        subway.step(2);
+        assert_eq!(subway.get_delayed_mass(), 1.);
+        let snapshot = SubwaySnapshot::from_vec(&subway.snapshot().to_vec()).unwrap();
+        subway.run_until(1e-12, 100);
+        assert_eq!(arrival_step(&subway), Some(arrival + 3));
+        assert_eq!(subway.get_visited_probability(127), 1.);
+        assert!(subway.get_unbalanced_steps().is_empty());
+
+        // waiting groups are kept in snapshots
+        let visited = subway.get_visited_probabilities();
+        assert!(subway.restore(&snapshot));
+        subway.jump_ahead(20);
+        assert_eq!(subway.get_visited_probabilities(), visited);
+    }
+
+    #[wasm_bindgen_test]
+    fn test_force_turn() {
+        // the group heading west turns back to the entrance
+        let mut subway = boss_corridor(MarkEffect::force_turn(Turn::Back));
+        assert_eq!(subway.movers.column(127).as_slice(), [0., 0., 0., 1.]);
+        subway.step(1);
+        assert_eq!(subway.movers.column(128).as_slice(), [0., 1., 0., 0.]);
+        assert_eq!(subway.get_edge_flow(127, 126), 0.);
+    }
+}
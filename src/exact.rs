@@ -0,0 +1,211 @@
+use num_rational::BigRational;
+use num_traits::{One, ToPrimitive, Zero};
+use wasm_bindgen::prelude::*;
+
+use crate::field::{Cell, Direction, Subway, DIRECTIONS};
+
+/// Largest denominator of movement probabilities
+const MAX_DENOMINATOR: i64 = 1_000_000;
+
+/// Recover the fraction with small denominator a floating-point probability stands for
+///
+/// Movement rules are given in floating point, but are actually simple fractions
+/// (like 0.85 = 17/20, or 1/3 for the entrance), so these are found
+/// as continued fraction convergents. A fraction is only accepted if it converts
+/// back to exactly the same floating-point number.
+fn rationalize(value: f64) -> Option<BigRational> {
+    if !value.is_finite() || value < 0. {
+        return None;
+    }
+    let (mut p0, mut q0, mut p1, mut q1) = (0i64, 1i64, 1i64, 0i64);
+    let mut x = value;
+    loop {
+        let a = x.floor();
+        let q2 = match (a as i64).checked_mul(q1).and_then(|q| q.checked_add(q0)) {
+            Some(q2) if q2 <= MAX_DENOMINATOR => q2,
+            _ => break,
+        };
+        let p2 = a as i64 * p1 + p0;
+        (p0, q0, p1, q1) = (p1, q1, p2, q2);
+        if p1 as f64 / q1 as f64 == value || x == a {
+            break;
+        }
+        x = 1. / (x - a);
+    }
+    (p1 as f64 / q1 as f64 == value).then(|| BigRational::new(p1.into(), q1.into()))
+}
+
+impl Subway {
+    /// Calculate visiting probabilities after `steps` steps with exact fractions
+    ///
+    /// This repeats `init` and `step` calls without any rounding errors:
+    /// probabilities of the movement model are turned into fractions, and
+    /// all movements are combined from them. Returns `None` if some probability
+    /// of the model is not a fraction with denominator up to a million, and
+    /// for fields with mark effects, which are not followed exactly.
+    pub fn exact_visited(&self, steps: u32) -> Option<Vec<BigRational>> {
+        if self.has_mark_effects() {
+            return None;
+        }
+        let mut visited = vec![BigRational::zero(); self.field.len()];
+        let mut movers = vec![[(); 4].map(|_| BigRational::zero()); self.field.len()];
+        // the group starts at the entrance, as in `init`
+        for (idx, visits) in visited.iter_mut().enumerate() {
+            if self.is_inner(idx) && self.field[idx] == Cell::Entrance {
+                *visits = BigRational::one();
+                let (next_cells, probs) =
+                    self.movement_probabilities(idx, Direction::South, 0, rationalize)?;
+                for (dir, next_idx) in next_cells.iter().enumerate() {
+                    movers[*next_idx][dir % 4] += &probs[dir];
+                }
+            }
+        }
+
+        for step_number in 1..=steps {
+            for _ in 0..self.modifiers.moves_per_step() {
+                let mut next_movers = vec![[(); 4].map(|_| BigRational::zero()); self.field.len()];
+                for (idx, cell_movers) in movers.iter().enumerate() {
+                    for d in DIRECTIONS {
+                        let mover_prob = &cell_movers[d as usize];
+                        if mover_prob.is_zero() {
+                            continue;
+                        }
+                        visited[idx] += mover_prob;
+                        for (next_idx, next_dir, prob) in
+                            self.exact_transitions(idx, d, step_number)?
+                        {
+                            next_movers[next_idx][next_dir] += mover_prob * prob;
+                        }
+                    }
+                }
+                movers = next_movers;
+            }
+        }
+        Some(visited)
+    }
+
+    /// Get non-zero transitions like `mover_transitions`, with exact probabilities
+    fn exact_transitions(
+        &self,
+        idx: usize,
+        heading: Direction,
+        move_count: u32,
+    ) -> Option<Vec<(usize, usize, BigRational)>> {
+        let (next_cells, probs) =
+            self.movement_probabilities(idx, heading.opposite(), move_count, rationalize)?;
+        Some(
+            probs
+                .into_iter()
+                .enumerate()
+                .filter(|(dir, prob)| next_cells[*dir] != idx && !prob.is_zero())
+                .map(|(dir, prob)| (next_cells[dir], (dir + heading as usize) % 4, prob))
+                .collect(),
+        )
+    }
+}
+
+#[wasm_bindgen]
+impl Subway {
+    /// Calculate visiting probabilities after `steps` steps with exact fractions
+    ///
+    /// Returns fractions formatted as "numerator/denominator" strings,
+    /// or nothing if the movement model has probabilities that are not fractions,
+    /// or the field has mark effects (see `exact_visited`).
+    pub fn exact_visited_probabilities(&self, steps: u32) -> Vec<String> {
+        self.exact_visited(steps).map_or(Vec::new(), |visited| {
+            visited.iter().map(|prob| prob.to_string()).collect()
+        })
+    }
+
+    /// Calculate the largest error of floating-point visiting probabilities after `steps` steps
+    ///
+    /// Returns nothing if exact probabilities can't be calculated.
+    pub fn exact_drift(&self, steps: u32) -> Option<f64> {
+        let exact = self.exact_visited(steps)?;
+        let mut scratch = self.scratch();
+        for step_number in 1..=steps {
+            scratch.step(step_number);
+        }
+        let drift = exact
+            .iter()
+            .zip(scratch.visited.iter())
+            .map(|(exact, &prob)| (exact.to_f64().unwrap_or(f64::NAN) - prob).abs())
+            .fold(0., f64::max);
+        Some(drift)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::effects::MarkEffect;
+    use crate::field::Mark;
+    use crate::movement::{DungeonModifiers, GodvilleMovement, MovementModel};
+    use std::rc::Rc;
+    use wasm_bindgen_test::*;
+
+    #[wasm_bindgen_test]
+    fn test_rationalize() {
+        let rationalize = |value| rationalize(value).map(|prob| prob.to_string());
+        assert_eq!(rationalize(0.).as_deref(), Some("0"));
+        assert_eq!(rationalize(1.).as_deref(), Some("1"));
+        assert_eq!(rationalize(0.85).as_deref(), Some("17/20"));
+        assert_eq!(rationalize(1. / 3.).as_deref(), Some("1/3"));
+        // not the fractions these were meant to be
+        assert_eq!(rationalize(0.1 + 0.2), None);
+        assert_eq!(rationalize(std::f64::consts::PI / 4.), None);
+    }
+
+    #[wasm_bindgen_test]
+    fn test_exact_loop() {
+        let mut subway = Subway::new();
+        subway.set_field(86, Cell::Pass);
+        subway.set_field(87, Cell::Pass);
+        subway.set_field(88, Cell::Pass);
+        subway.set_field(106, Cell::Pass);
+        subway.set_field(107, Cell::Pass);
+        subway.set_field(108, Cell::Pass);
+        subway.set_field(126, Cell::Pass);
+        subway.set_field(127, Cell::Exit);
+        subway.set_field(128, Cell::Entrance);
+        subway.init(&DungeonModifiers {
+            jumpy: true,
+            ..Default::default()
+        });
+
+        let visited = subway.exact_visited_probabilities(3);
+        assert_eq!(visited[127], "1/2");
+        assert_eq!(visited[128], "1");
+        assert_eq!(visited[88], "1/2");
+
+        // effects of marks are not followed
+        subway.set_mark(88, Mark::FinalBoss);
+        subway.set_mark_effect(Mark::FinalBoss, MarkEffect::absorb(0.5));
+        assert!(subway.exact_visited(3).is_none());
+        subway.set_mark_effect(Mark::FinalBoss, MarkEffect::none());
+        assert!(subway.exact_visited(3).is_some());
+
+        assert!(subway.exact_drift(60).unwrap() < 1e-12);
+
+        // rules that are not simple fractions can't be followed exactly
+        struct IrrationalMovement;
+        impl MovementModel for IrrationalMovement {
+            fn turn_probabilities(&self, walls: [bool; 4]) -> [f64; 4] {
+                GodvilleMovement.turn_probabilities(walls)
+            }
+            fn jump_probability(&self) -> f64 {
+                std::f64::consts::FRAC_1_SQRT_2
+            }
+            fn jump_start_move(&self) -> u32 {
+                1
+            }
+            fn entrance_exit_move(&self) -> u32 {
+                20
+            }
+        }
+        subway.set_model(Rc::new(IrrationalMovement));
+        assert!(subway.exact_visited(3).is_none());
+        assert!(subway.exact_visited_probabilities(3).is_empty());
+        assert_eq!(subway.exact_drift(3), None);
+    }
+}
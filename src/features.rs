@@ -1,4 +1,5 @@
-use crate::imga::{Mark, FeatureData};
+use crate::field::Mark;
+use crate::imga::FeatureData;
 pub const FEATURE_DATA: [(FeatureData, Mark); 38] = [
 	(FeatureData { x: 11, y: 11, bits_1: [20935737, 2734798361, 4260159929, 2185833650, 1217894208, 814393910], bits_2: [19757097, 2736895513, 4285329849, 2152409530, 2023200592, 814397990] }, Mark::Direction), // 2
 	(FeatureData { x: 11, y: 11, bits_1: [2703193497, 1744897557, 1772662589, 1174678683, 3986133152, 2982956790], bits_2: [2837411225, 673777431, 971550649, 3330550923, 3986133680, 2974568134] }, Mark::Direction), // 8
@@ -1,10 +1,26 @@
-use nalgebra::SMatrix;
-use nalgebra::SVector;
+use std::collections::VecDeque;
+use std::rc::Rc;
+
+use nalgebra::{DMatrix, DVector, SVector};
+use num_traits::Num;
 use wasm_bindgen::prelude::*;
 
+use crate::effects::{MarkEffect, MARK_COUNT};
+use crate::ledger::LedgerEntry;
+use crate::movement::{DungeonModifiers, GodvilleMovement, MovementModel};
+use crate::probe::FirstVisits;
+use crate::transitions::CompiledTransitions;
+
+/// Default field width
 pub const SIZE_X: usize = 20;
+/// Default field height
 pub const SIZE_Y: usize = 20;
-const FLAT_SIZE: usize = SIZE_X * SIZE_Y;
+
+/// Movers mass below which propagation is considered finished
+pub(crate) const PROPAGATION_EPSILON: f64 = 1e-12;
+
+/// Limit of propagated steps for groups that never leave the field
+pub(crate) const PROPAGATION_MAX_STEPS: u32 = 10000;
 
 #[wasm_bindgen]
 #[derive(Copy, Clone, PartialEq, Eq)]
@@ -15,14 +31,56 @@ pub enum Cell {
     Exit = 3,
 }
 
+/// Identity of a cell groups leave the field through
+#[wasm_bindgen]
 #[derive(Copy, Clone, PartialEq, Eq)]
-enum Direction {
+pub enum ExitKind {
+    Treasury = 0,
+    Subtreasury = 1,
+    /// exit without a mark
+    Extra = 2,
+    /// the entrance, after it turns into an exit
+    Entrance = 3,
+}
+
+/// Special object drawn on a cell
+#[wasm_bindgen]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Mark {
+    None = 0,
+    Wall = 1,
+    Entrance = 2,
+    Treasury = 3,
+    Subtreasury = 4,
+    FinalBoss = 5,
+    OtherBoss = 6,
+    Ladder = 7,
+    Trap = 8,
+    Luck = 9,
+    RaiseWall = 10,
+    Direction = 11,
+    Scarecrow = 12,
+    Fountain = 13,
+}
+
+/// Direction on the field, north being up
+#[wasm_bindgen]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Direction {
     North = 0,
     East = 1,
     South = 2,
     West = 3,
 }
 
+/// All directions in index order
+pub(crate) const DIRECTIONS: [Direction; 4] = [
+    Direction::North,
+    Direction::East,
+    Direction::South,
+    Direction::West,
+];
+
 impl Direction {
     pub fn opposite(self) -> Self {
         match self {
@@ -47,13 +105,16 @@ impl std::ops::Add for Direction {
 }
 
 /// CellField: subway field, linearized
-pub type CellField = [Cell; FLAT_SIZE];
+pub type CellField = Vec<Cell>;
 
 /// VisitedField: calculated visiting probabilities
-pub type VisitedField = SVector<f64, FLAT_SIZE>;
+pub type VisitedField = DVector<f64>;
 
 /// MoverField: "part" of initial group, 4 directions, upon entering a cell
-type MoverField = SMatrix<f64, 4, FLAT_SIZE>;
+pub(crate) type MoverField = DMatrix<f64>;
+
+/// FlowField: expected moves out of a cell, 4 directions walking and 4 jumping
+pub(crate) type FlowField = DMatrix<f64>;
 
 /// DirVec: movement probability, 4 directions normal and jump
 type DirVec = SVector<f64, 8>;
@@ -63,24 +124,80 @@ type DirIndexVec = [usize; 8];
 
 /// Game field
 #[wasm_bindgen]
+#[derive(Clone)]
 pub struct Subway {
+    /// number of rows, including guard rails
+    pub(crate) rows: usize,
+
+    /// number of columns, including guard rails
+    pub(crate) cols: usize,
+
     /// game field
-    field: CellField,
+    pub(crate) field: CellField,
+
+    /// identity of exit cells (ignored for other cells)
+    pub(crate) exit_kinds: Vec<ExitKind>,
+
+    /// marks drawn on cells
+    pub(crate) marks: Vec<Mark>,
+
+    /// directions signs of cells point to
+    pub(crate) signs: Vec<Option<Direction>>,
+
+    /// effects of marks on groups, by mark
+    pub(crate) mark_effects: [MarkEffect; MARK_COUNT],
+
+    /// movers waiting in delaying cells, by number of moves left to wait
+    pub(crate) delayed: VecDeque<MoverField>,
 
     /// accumulated probabilities of visiting a cell
-    visited: VisitedField,
+    pub(crate) visited: VisitedField,
 
     /// movers after last calculated step
     ///
     /// Entry point has initially 100% of the group and it creates
     /// movers in all possible directions. Movers are assigned to the
     /// cell they will visit on next step in the direction of movement
-    /// (hence MoveField is 4xFLAT_SIZE), and have the weight according to
+    /// (hence MoveField is 4 x number of cells), and have the weight according to
     /// movement probability distribution
-    movers: MoverField,
+    pub(crate) movers: MoverField,
+
+    /// accumulated expected moves out of each cell, by direction of movement
+    pub(crate) flows: FlowField,
+
+    /// dungeon modifiers
+    pub(crate) modifiers: DungeonModifiers,
+
+    /// movement rules
+    pub(crate) model: Rc<dyn MovementModel>,
+
+    /// mass leaving the field on each step, for every exit cell (and the entrance)
+    pub(crate) arrivals: Vec<(usize, Vec<f64>)>,
+
+    /// number of the last performed step
+    pub(crate) last_step: u32,
+
+    /// accumulated visits of the entrance before it turns into an exit
+    /// (including the start of the group)
+    pub(crate) early_entrance_visits: f64,
 
-    /// jump moves enabled
-    jumpy: bool,
+    /// mass balance of each performed step
+    pub(crate) ledger: Vec<LedgerEntry>,
+
+    /// check mass balance after each step
+    pub(crate) ledger_checks: bool,
+
+    /// steps that failed the mass balance check
+    pub(crate) unbalanced_steps: Vec<u32>,
+
+    /// transitions precompiled by `init`
+    pub(crate) transitions: Rc<CompiledTransitions>,
+
+    /// track first visits of cells from the next `init`
+    pub(crate) first_visit_tracking: bool,
+
+    /// movers that have not visited tracked cells yet
+    pub(crate) first_visits: Option<FirstVisits>,
 }
 impl Default for Subway {
     fn default() -> Self {
@@ -95,60 +212,241 @@ pub struct Coordinate {
     pub col: usize,
 }
 
+/// Outcome of running the simulation until convergence
+#[wasm_bindgen]
+#[derive(Clone, Copy)]
+pub struct RunSummary {
+    /// number of steps performed
+    pub steps: u32,
+    /// number of the last performed step
+    pub last_step: u32,
+    /// movers mass still inside the field
+    pub remaining: f64,
+    /// remaining mass fell below the threshold
+    pub converged: bool,
+}
+
 #[wasm_bindgen]
 impl Subway {
     #[wasm_bindgen(constructor)]
     pub fn new() -> Self {
+        Self::with_size(SIZE_Y, SIZE_X)
+    }
+
+    /// Create a field of `rows` x `cols` cells
+    ///
+    /// Outer rows and columns are guard rails and always stay walls,
+    /// so fields smaller than 3 x 3 are enlarged to fit at least one cell.
+    pub fn with_size(rows: usize, cols: usize) -> Self {
+        let (rows, cols) = (rows.max(3), cols.max(3));
         Subway {
-            field: [Cell::Wall; FLAT_SIZE],
-            visited: SVector::zeros(),
-            movers: SMatrix::zeros(),
-            jumpy: false,
+            rows,
+            cols,
+            field: vec![Cell::Wall; rows * cols],
+            exit_kinds: vec![ExitKind::Extra; rows * cols],
+            marks: vec![Mark::None; rows * cols],
+            signs: vec![None; rows * cols],
+            mark_effects: [MarkEffect::default(); MARK_COUNT],
+            delayed: VecDeque::new(),
+            visited: VisitedField::zeros(rows * cols),
+            movers: MoverField::zeros(4, rows * cols),
+            flows: FlowField::zeros(8, rows * cols),
+            modifiers: DungeonModifiers::default(),
+            model: Rc::new(GodvilleMovement),
+            arrivals: Vec::new(),
+            last_step: 0,
+            early_entrance_visits: 0.,
+            ledger: Vec::new(),
+            ledger_checks: false,
+            unbalanced_steps: Vec::new(),
+            transitions: Rc::default(),
+            first_visit_tracking: false,
+            first_visits: None,
         }
     }
 
+    /// Get number of rows
+    pub fn get_rows(&self) -> usize {
+        self.rows
+    }
+    /// Get number of columns
+    pub fn get_cols(&self) -> usize {
+        self.cols
+    }
+    /// Get number of cells
+    pub fn get_flat_size(&self) -> usize {
+        self.rows * self.cols
+    }
+
     /// Convert (row, column) to linear index
-    pub fn to_idx(row: usize, col: usize) -> usize {
-        row * SIZE_X + col
+    pub fn to_idx(&self, row: usize, col: usize) -> usize {
+        row * self.cols + col
     }
     /// Convert linear index to (row, column)
-    pub fn from_idx(idx: usize) -> Coordinate {
+    pub fn to_coordinate(&self, idx: usize) -> Coordinate {
         Coordinate {
-            row: idx / SIZE_X,
-            col: idx % SIZE_X,
+            row: idx / self.cols,
+            col: idx % self.cols,
         }
     }
+    /// Tell if cell `idx` lies inside guard rails
+    pub fn is_inner(&self, idx: usize) -> bool {
+        let Coordinate { row, col } = self.to_coordinate(idx);
+        (1..self.rows - 1).contains(&row) && (1..self.cols - 1).contains(&col)
+    }
 
     /// Set cell type
-    pub fn set_field(&mut self, idx: usize, cell: Cell) {
-        let Coordinate { row, col } = Self::from_idx(idx);
-        if (1..=SIZE_X - 2).contains(&row) && (1..=SIZE_Y - 2).contains(&col) {
-            self.field[idx] = cell;
+    ///
+    /// Returns false if the cell is out of the field or on guard rails,
+    /// which always stay walls.
+    pub fn set_field(&mut self, idx: usize, cell: Cell) -> bool {
+        if !self.is_inner(idx) {
+            return false;
+        }
+        // keep recorded arrivals in line with exits of the field
+        let was_exit = matches!(self.field[idx], Cell::Exit | Cell::Entrance);
+        let is_exit = matches!(cell, Cell::Exit | Cell::Entrance);
+        if was_exit && !is_exit {
+            self.arrivals.retain(|(exit, _)| *exit != idx);
+        } else if !was_exit && is_exit {
+            let pos = self.arrivals.partition_point(|(exit, _)| *exit < idx);
+            let series = vec![0.; self.last_step as usize];
+            self.arrivals.insert(pos, (idx, series));
+        }
+        self.field[idx] = cell;
+        self.exit_kinds[idx] = ExitKind::Extra;
+        self.drop_transitions();
+        true
+    }
+    /// Make cell an exit of `kind`
+    ///
+    /// Returns false if the cell cannot be changed (see `set_field`),
+    /// or `kind` is not an identity of an exit cell.
+    pub fn set_exit(&mut self, idx: usize, kind: ExitKind) -> bool {
+        if kind == ExitKind::Entrance || !self.set_field(idx, Cell::Exit) {
+            return false;
+        }
+        self.exit_kinds[idx] = kind;
+        true
+    }
+    /// Get identity of the exit at cell `idx`, if there is one
+    pub fn get_exit_kind(&self, idx: usize) -> Option<ExitKind> {
+        match self.field.get(idx) {
+            Some(Cell::Exit) => Some(self.exit_kinds[idx]),
+            Some(Cell::Entrance) => Some(ExitKind::Entrance),
+            _ => None,
         }
     }
     /// Get cell type
     pub fn get_field(&self, idx: usize) -> Cell {
         self.field[idx]
     }
+    /// Set mark of a cell, independently of its type
+    ///
+    /// Signs are removed with `Mark::Direction` marks. Marks of exits
+    /// set their identity: treasury marks make treasuries and sub-treasuries,
+    /// other marks make extra exits.
+    /// Returns false if the cell is out of the field or on guard rails.
+    pub fn set_mark(&mut self, idx: usize, mark: Mark) -> bool {
+        if !self.is_inner(idx) {
+            return false;
+        }
+        if self.marks[idx] == Mark::Direction && mark != Mark::Direction {
+            self.clear_sign(idx);
+        }
+        if self.field[idx] == Cell::Exit {
+            self.exit_kinds[idx] = match mark {
+                Mark::Treasury => ExitKind::Treasury,
+                Mark::Subtreasury => ExitKind::Subtreasury,
+                _ => ExitKind::Extra,
+            };
+        }
+        self.marks[idx] = mark;
+        true
+    }
+    /// Get mark of a cell
+    pub fn get_mark(&self, idx: usize) -> Mark {
+        self.marks.get(idx).copied().unwrap_or(Mark::None)
+    }
+    /// Put a sign pointing to `direction` on a cell
+    ///
+    /// Groups entering the cell go where the sign points, with the probability
+    /// given by `MovementModel::sign_probability`, unless there is a wall.
+    /// Returns false if the cell is out of the field or on guard rails.
+    pub fn set_sign(&mut self, idx: usize, direction: Direction) -> bool {
+        if !self.is_inner(idx) {
+            return false;
+        }
+        self.signs[idx] = Some(direction);
+        self.drop_transitions();
+        true
+    }
+    /// Remove sign of a cell
+    pub fn clear_sign(&mut self, idx: usize) {
+        if let Some(sign) = self.signs.get_mut(idx) {
+            *sign = None;
+            self.drop_transitions();
+        }
+    }
+    /// Get direction the sign of a cell points to, if there is one
+    pub fn get_sign(&self, idx: usize) -> Option<Direction> {
+        self.signs.get(idx).copied().flatten()
+    }
+    /// Get indices of all cells with `mark`
+    pub fn get_mark_cells(&self, mark: Mark) -> Vec<usize> {
+        (0..self.marks.len())
+            .filter(|&idx| self.marks[idx] == mark)
+            .collect()
+    }
     /// Get probability for a cell
     pub fn get_visited_probability(&self, idx: usize) -> f64 {
         self.visited[idx]
     }
-
-    /// Mover directions when looking from south
-    fn mover_probability(walls: [bool; 4]) -> [f64; 4] {
-        match walls {
-            [true, true, true, true] => [0., 0., 0., 0.],
-            [true, true, true, false] => [0., 0., 0., 1.],
-            [true, true, false, true] => [0., 0., 1., 0.],
-            [true, false, true, true] => [0., 1., 0., 0.],
-            [false, true, true, true] => [1., 0., 0., 0.],
-            [true, true, _, false] => [0., 0., 0., 1.],
-            [true, false, _, true] => [0., 0.8, 0.2, 0.],
-            [true, false, _, false] => [0., 0.8, 0., 0.2],
-            [false, true, _, _] => [1., 0., 0., 0.],
-            [false, false, _, _] => [0.85, 0.15, 0., 0.],
-        }
+    /// Get probabilities for all cells
+    pub fn get_visited_probabilities(&self) -> Vec<f64> {
+        self.visited.as_slice().to_vec()
+    }
+    /// Get number of the last performed step
+    pub fn get_last_step(&self) -> u32 {
+        self.last_step
+    }
+    /// Get indices of cells movers can leave the field through
+    pub fn get_exit_cells(&self) -> Vec<usize> {
+        self.arrivals.iter().map(|(idx, _)| *idx).collect()
+    }
+    /// Get mass leaving the field through cell `idx` on each step
+    ///
+    /// Element `k` holds the probability of leaving on step `k + 1`.
+    /// Entrance only collects its visitors after it turns into an exit.
+    pub fn get_arrivals(&self, idx: usize) -> Vec<f64> {
+        self.arrivals
+            .iter()
+            .find(|(exit_idx, _)| *exit_idx == idx)
+            .map(|(_, series)| series.clone())
+            .unwrap_or_default()
+    }
+    /// Get probability of leaving the field through the entrance
+    ///
+    /// Unlike visiting probability of the entrance cell, this does not count
+    /// visitors who passed it before it turned into an exit.
+    pub fn get_entrance_exit_probability(&self) -> f64 {
+        self.arrivals
+            .iter()
+            .filter(|(idx, _)| self.field[*idx] == Cell::Entrance)
+            .map(|(_, series)| series.iter().sum::<f64>())
+            .sum()
+    }
+    /// Get probability of leaving the field through exits of `kind`
+    pub fn get_exit_probability(&self, kind: ExitKind) -> f64 {
+        self.arrivals
+            .iter()
+            .filter(|(idx, _)| self.get_exit_kind(*idx) == Some(kind))
+            .map(|(_, series)| series.iter().sum::<f64>())
+            .sum()
+    }
+    /// Get accumulated entrance visits before it turned into an exit
+    pub fn get_early_entrance_visits(&self) -> f64 {
+        self.early_entrance_visits
     }
 
     /// Get possible movements (with move count dynamics) from current cell `idx`
@@ -163,38 +461,54 @@ impl Subway {
         in_direction: Direction,
         move_count: u32,
     ) -> (DirIndexVec, DirVec) {
-        const JUMP_PROBABILITY: f64 = 0.2;
+        let (offsets, probs) = self
+            .movement_probabilities(idx, in_direction, move_count, Some)
+            .expect("floating-point probabilities are always converted");
+        (offsets, DirVec::from(probs))
+    }
 
+    /// Get possible movements like `get_movement`, with probabilities of type `T`
+    ///
+    /// Probabilities of the movement model are turned into `T` with `convert`
+    /// and combined in `T` arithmetic. Returns `None` if a probability
+    /// can't be converted.
+    pub(crate) fn movement_probabilities<T: Clone + Num>(
+        &self,
+        idx: usize,
+        in_direction: Direction,
+        move_count: u32,
+        convert: impl Fn(f64) -> Option<T>,
+    ) -> Option<(DirIndexVec, [T; 8])> {
+        let mut result: [T; 8] = std::array::from_fn(|_| T::zero());
         // Walls and exit conditions
-        if (self.field[idx] == Cell::Wall)
-            || (self.field[idx] == Cell::Entrance && move_count >= 20)
-            || (self.field[idx] == Cell::Exit)
-        {
-            return ([idx; 8], DirVec::zeros());
+        if (self.field[idx] == Cell::Wall) || self.is_exit(idx, move_count) {
+            return Some(([idx; 8], result));
         }
         // cell indices for relative directions
+        let cols = self.cols;
         let indices = [
-            idx + SIZE_X, // when going from north, next north cell is here
+            idx + cols, // when going from north, next north cell is here
             idx.saturating_sub(1),
-            idx.saturating_sub(SIZE_X),
+            idx.saturating_sub(cols),
             idx + 1,
-            idx + SIZE_X, // cycle
+            idx + cols, // cycle
             idx.saturating_sub(1),
-            idx.saturating_sub(SIZE_X),
+            idx.saturating_sub(cols),
         ];
         let jump_indices = [
-            idx + 2 * SIZE_X, // when going from north, next north cell is here
+            idx + 2 * cols, // when going from north, next north cell is here
             idx.saturating_sub(2),
-            idx.saturating_sub(2 * SIZE_X),
+            idx.saturating_sub(2 * cols),
             idx + 2,
-            idx + 2 * SIZE_X, // cycle
+            idx + 2 * cols, // cycle
             idx.saturating_sub(2),
-            idx.saturating_sub(2 * SIZE_X),
+            idx.saturating_sub(2 * cols),
         ];
+        let last_idx = self.field.len() - 1;
         let mut offsets: DirIndexVec = [0; 8];
         for i in 0..4 {
-            offsets[i] = indices[in_direction as usize + i].min(FLAT_SIZE - 1);
-            offsets[i + 4] = jump_indices[in_direction as usize + i].min(FLAT_SIZE - 1)
+            offsets[i] = indices[in_direction as usize + i].min(last_idx);
+            offsets[i + 4] = jump_indices[in_direction as usize + i].min(last_idx)
         }
         let walls = [
             self.field[offsets[0]] == Cell::Wall,
@@ -208,105 +522,375 @@ impl Subway {
             self.field[offsets[6]] == Cell::Wall,
             self.field[offsets[7]] == Cell::Wall,
         ];
-        let can_jump = self.jumpy && move_count >= 5 && !jump_walls.iter().all(|&v| v);
+        let can_jump = self.modifiers.jumpy
+            && move_count >= self.model.jump_start_move()
+            && !jump_walls.iter().all(|&v| v);
 
         if move_count == 0 && self.field[idx] == Cell::Entrance {
-            // when initializing, movement at entrance is equally random
+            // when initializing, movement at entrance follows its own rules
             // (and walk only)
-            let num_freeways = walls.iter().filter(|&&x| !x).count();
-            let prob = 1.0 / num_freeways as f64;
-            let mut probs = DirVec::zeros();
+            let probs = self.model.entrance_probabilities(walls);
             for i in 0..4 {
-                probs[i] = if walls[i] { 0. } else { prob }
+                result[i] = convert(probs[i])?;
             }
-            return (offsets, probs);
+            return Some((offsets, result));
         }
-        let mut result = DirVec::zeros();
         if !can_jump {
-            result.as_mut_slice()[..4].copy_from_slice(&Subway::mover_probability(walls));
+            let move_probs = self.turn_probabilities(walls);
+            for i in 0..4 {
+                result[i] = convert(move_probs[i])?;
+            }
         } else {
-            let move_probs = Subway::mover_probability(walls);
-            let jump_probs = Subway::mover_probability(jump_walls);
+            let jump_probability = convert(self.model.jump_probability())?;
+            let move_probs = self.turn_probabilities(walls);
+            let jump_probs = self.turn_probabilities(jump_walls);
             for i in 0..4 {
-                result[i] = move_probs[i] * (1. - JUMP_PROBABILITY);
-                result[i + 4] = jump_probs[i] * JUMP_PROBABILITY;
+                result[i] = convert(move_probs[i])? * (T::one() - jump_probability.clone());
+                result[i + 4] = convert(jump_probs[i])? * jump_probability.clone();
             }
         }
-        (offsets, result)
+        if let Some(sign) = self.signs[idx] {
+            // relative direction the sign points to
+            let turn = (sign as usize + 6 - in_direction as usize) % 4;
+            if !walls[turn] {
+                let sign_probability = convert(self.model.sign_probability())?;
+                for prob in result.iter_mut() {
+                    *prob = prob.clone() * (T::one() - sign_probability.clone());
+                }
+                result[turn] = result[turn].clone() + sign_probability;
+            }
+        }
+        Some((offsets, result))
+    }
+
+    /// Movement probabilities in relative directions, with modifiers applied
+    fn turn_probabilities(&self, walls: [bool; 4]) -> [f64; 4] {
+        self.modifiers.turn_probabilities(self.model.as_ref(), walls)
+    }
+
+    /// Tell if movers arriving at `idx` on move `move_count` leave the field
+    pub(crate) fn is_exit(&self, idx: usize, move_count: u32) -> bool {
+        match self.field[idx] {
+            Cell::Exit => true,
+            Cell::Entrance => move_count >= self.model.entrance_exit_move(),
+            _ => false,
+        }
+    }
+
+    /// Get non-zero transitions of a mover in cell `idx` moving in direction `heading`.
+    ///
+    /// Yields (next cell index, next heading, probability) triples.
+    pub(crate) fn mover_transitions(
+        &self,
+        idx: usize,
+        heading: Direction,
+        move_count: u32,
+    ) -> impl Iterator<Item = (usize, usize, f64)> {
+        self.mover_moves(idx, heading, move_count)
+            .map(|(_, next_idx, next_heading, prob)| (next_idx, next_heading, prob))
+    }
+
+    /// Get non-zero moves of a mover in cell `idx` moving in direction `heading`.
+    ///
+    /// Yields (relative direction, next cell index, next heading, probability),
+    /// with relative directions 4 to 7 being jumps.
+    pub(crate) fn mover_moves(
+        &self,
+        idx: usize,
+        heading: Direction,
+        move_count: u32,
+    ) -> impl Iterator<Item = (usize, usize, usize, f64)> {
+        let (next_cells, probs) = self.get_movement(idx, heading.opposite(), move_count);
+        (0..8).filter_map(move |dir| {
+            if next_cells[dir] != idx && probs[dir] != 0. {
+                Some((
+                    dir,
+                    next_cells[dir],
+                    (dir + heading as usize) % 4,
+                    probs[dir],
+                ))
+            } else {
+                None
+            }
+        })
     }
 
     /// Initialize probability matrix for first step
-    pub fn init(&mut self, jumpy: bool) {
-        self.visited = SVector::zeros();
-        self.movers = SMatrix::zeros();
-        self.jumpy = jumpy;
-        for idx in 0..FLAT_SIZE {
-            let x = idx % SIZE_X;
-            let y = idx / SIZE_X;
+    pub fn init(&mut self, modifiers: &DungeonModifiers) {
+        self.visited = VisitedField::zeros(self.field.len());
+        self.movers = MoverField::zeros(4, self.field.len());
+        self.flows = FlowField::zeros(8, self.field.len());
+        self.modifiers = *modifiers;
+        self.arrivals.clear();
+        self.last_step = 0;
+        self.early_entrance_visits = 0.;
+        self.ledger.clear();
+        self.unbalanced_steps.clear();
+        self.delayed.clear();
+        for idx in 0..self.field.len() {
             // Guard rails
-            if x == 0 || x == SIZE_X - 1 || y == 0 || y == SIZE_Y - 1 {
+            if !self.is_inner(idx) {
                 continue;
             }
 
+            if matches!(self.field[idx], Cell::Exit | Cell::Entrance) {
+                self.arrivals.push((idx, Vec::new()));
+            }
+
             // Set entry point probability
             if self.field[idx] == Cell::Entrance {
                 self.visited[idx] = 1.0;
+                self.early_entrance_visits += 1.0;
                 let (move_idx, move_probs) = self.get_movement(idx, Direction::South, 0);
                 for (dir, next_idx) in move_idx.iter().enumerate() {
                     self.movers[(dir % 4, *next_idx)] = move_probs[dir];
+                    self.flows[(dir, idx)] += move_probs[dir];
                 }
             }
         }
+        self.compile_transitions();
+        self.first_visits = None;
+        if self.first_visit_tracking {
+            let cells = self.first_visit_cells().map(Some).collect();
+            self.start_first_visits(cells);
+        }
     }
 
     /// Perform a mover step
     pub fn step(&mut self, step_number: u32) {
-        let mut next_movers = MoverField::zeros();
-        let zero_dir = SVector::zeros();
+        let mut entry = self.begin_step(step_number);
+        for _ in 0..self.modifiers.moves_per_step() {
+            self.make_move(step_number, &mut entry);
+        }
+        self.finish_step(entry);
+    }
 
+    /// Perform steps until movers mass falls below `epsilon`, or `max_steps` steps are done
+    ///
+    /// Stepping continues from the last performed step.
+    pub fn run_until(&mut self, epsilon: f64, max_steps: u32) -> RunSummary {
+        let mut steps = 0;
+        while steps < max_steps && self.in_flight_mass() >= epsilon {
+            self.step(self.last_step + 1);
+            steps += 1;
+        }
+        let remaining = self.in_flight_mass();
+        RunSummary {
+            steps,
+            last_step: self.last_step,
+            remaining,
+            converged: remaining < epsilon,
+        }
+    }
+
+    /// Move all movers once, accounting for the mass in `entry`
+    fn make_move(&mut self, step_number: u32, entry: &mut LedgerEntry) {
+        self.arrive(step_number, entry);
+        self.depart(step_number, entry);
+    }
+
+    /// Start a step, returning its ledger entry to fill in
+    pub(crate) fn begin_step(&mut self, step_number: u32) -> LedgerEntry {
+        // start records of this step over
+        let step_pos = step_number.max(1) as usize - 1;
+        for (_, series) in self.arrivals.iter_mut() {
+            if series.len() <= step_pos {
+                series.resize(step_pos + 1, 0.);
+            }
+            series[step_pos] = 0.;
+        }
+
+        LedgerEntry {
+            step: step_number,
+            start: self.in_flight_mass(),
+            ..Default::default()
+        }
+    }
+
+    /// Finish a step started with `begin_step`
+    pub(crate) fn finish_step(&mut self, mut entry: LedgerEntry) {
+        entry.in_flight = self.in_flight_mass();
+        self.record_ledger(entry);
+        self.last_step = entry.step;
+    }
+
+    /// Movers mass inside the field, including groups waiting in delaying cells
+    pub(crate) fn in_flight_mass(&self) -> f64 {
+        self.movers.sum() + self.get_delayed_mass()
+    }
+
+    /// Count movers in their cells, first half of a move
+    ///
+    /// Movers in exits are recorded as leaving the field, and effects
+    /// of marks are applied.
+    pub(crate) fn arrive(&mut self, step_number: u32, entry: &mut LedgerEntry) {
         // update probability matrix: add all movers locations.
         // This will go over 100% for cells visited multiple times,
         // but will have correct counts for exit points
         let movers_sum = self.movers.row_sum_tr();
-        self.visited += movers_sum;
+        self.visited += &movers_sum;
 
-        // move movers (inside guard rails)
-        for idx in SIZE_X..FLAT_SIZE - SIZE_X {
-            if self.movers.column(idx).eq(&zero_dir) {
+        // record movers leaving the field on this step
+        let step_pos = step_number.max(1) as usize - 1;
+        for exit in 0..self.arrivals.len() {
+            let idx = self.arrivals[exit].0;
+            if self.is_exit(idx, step_number) {
+                self.arrivals[exit].1[step_pos] += movers_sum[idx];
+                if self.field[idx] == Cell::Entrance {
+                    entry.entrance += movers_sum[idx];
+                } else {
+                    entry.exits += movers_sum[idx];
+                }
+            } else if self.field[idx] == Cell::Entrance {
+                self.early_entrance_visits += movers_sum[idx];
+            }
+        }
+        self.apply_mark_effects(step_number, entry);
+        self.arrive_first_visits(step_number);
+    }
+
+    /// Move movers out of their cells, second half of a move
+    pub(crate) fn depart(&mut self, step_number: u32, entry: &mut LedgerEntry) {
+        let mut flows = std::mem::replace(&mut self.flows, FlowField::zeros(0, 0));
+        let (next_movers, lost) =
+            self.advance_with_losses(&self.movers, step_number, Some(&mut flows));
+        self.movers = next_movers;
+        self.flows = flows;
+        entry.lost += lost;
+        self.depart_first_visits(step_number);
+    }
+
+    /// Calculate positions of `movers` after a single move
+    ///
+    /// Movers at exits leave the field and are not carried over.
+    pub(crate) fn advance(&self, movers: &MoverField, step_number: u32) -> MoverField {
+        self.advance_with_losses(movers, step_number, None).0
+    }
+
+    /// Calculate positions of `movers` after a single move, and the mass lost on the way
+    ///
+    /// Movers at exits leave the field and are not lost. Movers that cannot go anywhere
+    /// (in cells enclosed by walls, or outside guard rails) are.
+    /// Moves between cells are added to `flows`, if given.
+    pub(crate) fn advance_with_losses(
+        &self,
+        movers: &MoverField,
+        step_number: u32,
+        mut flows: Option<&mut FlowField>,
+    ) -> (MoverField, f64) {
+        if let Some(phase) = self.transitions.get(step_number) {
+            let flows = flows.map(|flows| flows.as_mut_slice());
+            let (next_movers, lost) = phase.advance(movers.as_slice(), flows);
+            return (MoverField::from_vec(4, self.field.len(), next_movers), lost);
+        }
+
+        let mut next_movers = MoverField::zeros(4, self.field.len());
+        let mut lost = 0.;
+
+        for idx in 0..self.field.len() {
+            if movers.column(idx).iter().all(|&v| v == 0.) {
+                continue;
+            }
+            // move movers (inside guard rails)
+            if !self.is_inner(idx) {
+                lost += movers.column(idx).sum();
                 continue;
             }
-            for d in [
-                Direction::North,
-                Direction::East,
-                Direction::South,
-                Direction::West,
-            ] {
-                let mover_prob = self.movers[(d as usize, idx)];
+            let is_exit = self.is_exit(idx, step_number);
+            for d in DIRECTIONS {
+                let mover_prob = movers[(d as usize, idx)];
                 if mover_prob == 0. {
                     continue;
                 }
 
-                let (mover_next_cells, probs) = self.get_movement(idx, d.opposite(), step_number);
-                for (dir, &next_idx) in mover_next_cells.iter().enumerate() {
-                    if next_idx != idx {
-                        // combine movers
-                        next_movers[((dir + d as usize) % 4, next_idx)] += probs[dir] * mover_prob;
+                let mut moved = 0.;
+                for (dir, next_idx, next_dir, prob) in self.mover_moves(idx, d, step_number) {
+                    // combine movers
+                    next_movers[(next_dir, next_idx)] += prob * mover_prob;
+                    moved += prob;
+                    if let Some(flows) = flows.as_deref_mut() {
+                        flows[(dir / 4 * 4 + next_dir, idx)] += prob * mover_prob;
                     }
                 }
+                if !is_exit {
+                    lost += mover_prob * (1. - moved).max(0.);
+                }
             }
         }
 
-        // copy over new data
-        for i in 0..FLAT_SIZE {
-            self.movers.set_column(i, &next_movers.column(i));
-        }
+        (next_movers, lost)
     }
 
     /// Reset the field to initial state
     pub fn reset(&mut self) {
-        self.field = [Cell::Wall; FLAT_SIZE];
-        self.visited = SVector::zeros();
-        self.movers = SMatrix::zeros();
+        self.field.fill(Cell::Wall);
+        self.exit_kinds.fill(ExitKind::Extra);
+        self.marks.fill(Mark::None);
+        self.signs.fill(None);
+        self.visited = VisitedField::zeros(self.field.len());
+        self.movers = MoverField::zeros(4, self.field.len());
+        self.flows = FlowField::zeros(8, self.field.len());
+        self.arrivals.clear();
+        self.last_step = 0;
+        self.early_entrance_visits = 0.;
+        self.ledger.clear();
+        self.unbalanced_steps.clear();
+        self.delayed.clear();
+        self.first_visits = None;
+        self.drop_transitions();
+    }
+
+    /// Change field dimensions, resetting it to initial state
+    pub fn resize(&mut self, rows: usize, cols: usize) {
+        self.rows = rows.max(3);
+        self.cols = cols.max(3);
+        self.field = vec![Cell::Wall; self.rows * self.cols];
+        self.exit_kinds = vec![ExitKind::Extra; self.rows * self.cols];
+        self.marks = vec![Mark::None; self.rows * self.cols];
+        self.signs = vec![None; self.rows * self.cols];
+        self.reset();
+    }
+}
+
+impl Subway {
+    /// Create a field with custom movement rules
+    pub fn with_model(model: Rc<dyn MovementModel>) -> Self {
+        Subway {
+            model,
+            ..Self::new()
+        }
+    }
+
+    /// Replace movement rules (applied starting with the next step)
+    pub fn set_model(&mut self, model: Rc<dyn MovementModel>) {
+        self.model = model;
+        self.drop_transitions();
+    }
+
+    /// Copy of the field initialized for an analysis to step on its own
+    ///
+    /// First visits are not tracked in the copy, no analysis reads them.
+    pub(crate) fn scratch(&self) -> Subway {
+        let mut scratch = self.clone();
+        scratch.first_visit_tracking = false;
+        scratch.first_visits = None;
+        scratch.init(&self.modifiers);
+        scratch
+    }
+}
+
+#[cfg(test)]
+impl Subway {
+    /// Loop around the center, with an exit next to the entrance,
+    /// so the exit is either reached at once, or on the way around
+    pub(crate) fn loop_field() -> Subway {
+        let mut subway = Subway::new();
+        for idx in [86, 87, 88, 106, 108, 126, 146, 147, 148] {
+            subway.set_field(idx, Cell::Pass);
+        }
+        subway.set_field(127, Cell::Exit);
+        subway.set_field(128, Cell::Entrance);
+        subway
     }
 }
 
@@ -334,7 +918,7 @@ mod tests {
         subway.set_field(126, Cell::Pass);
         subway.set_field(125, Cell::Exit);
 
-        subway.init(false);
+        subway.init(&DungeonModifiers::default());
 
         let (indices, probs) = subway.get_movement(127, Direction::East, 1);
         assert_eq!(indices[..4], [126, 107, 128, 147]);
@@ -353,7 +937,7 @@ mod tests {
         subway.set_field(126, Cell::Pass);
         subway.set_field(125, Cell::Exit);
 
-        subway.init(false);
+        subway.init(&DungeonModifiers::default());
 
         assert_eq!(subway.get_field(128), Cell::Entrance);
         assert_eq!(subway.visited[128], 1.);
@@ -418,7 +1002,7 @@ mod tests {
         subway.set_field(126, Cell::Pass);
         subway.set_field(127, Cell::Exit);
         subway.set_field(128, Cell::Entrance);
-        subway.init(false);
+        subway.init(&DungeonModifiers::default());
         subway.step(1);
         subway.step(2);
         subway.step(3);
@@ -432,4 +1016,311 @@ mod tests {
         assert!(subway.visited[127] > 0.5, "Two paths converged");
         assert_eq!(subway.visited[107], 0.0, "Center point not visited");
     }
+
+    #[wasm_bindgen_test]
+    fn test_arrivals() {
+        let mut subway = Subway::new();
+        for idx in [86, 87, 88, 106, 107, 108, 126] {
+            subway.set_field(idx, Cell::Pass);
+        }
+        subway.set_field(127, Cell::Exit);
+        subway.set_field(128, Cell::Entrance);
+        subway.init(&DungeonModifiers::default());
+        for step_number in 1..=7 {
+            subway.step(step_number);
+        }
+
+        assert_eq!(subway.get_exit_cells(), [127, 128]);
+        assert_eq!(
+            subway.get_arrivals(127),
+            [0.5, 0., 0., 0., 0., 0., 0.5],
+            "Two paths arrive at different steps"
+        );
+        assert_eq!(subway.get_arrivals(128), [0.; 7]);
+        assert!(subway.get_arrivals(126).is_empty());
+
+        // exits drawn after `init` are recorded from then on
+        subway.set_field(126, Cell::Exit);
+        subway.set_field(127, Cell::Pass);
+        assert_eq!(subway.get_exit_cells(), [126, 128]);
+        assert_eq!(subway.get_arrivals(126), [0.; 7]);
+        assert!(subway.get_arrivals(127).is_empty());
+        subway.step(8);
+        assert_eq!(subway.get_arrivals(126).len(), 8);
+    }
+
+    #[wasm_bindgen_test]
+    fn test_entrance_exit() {
+        // dead end: group bounces between the entrance and the end of corridor
+        let mut subway = Subway::new();
+        subway.set_field(128, Cell::Entrance);
+        subway.set_field(127, Cell::Pass);
+        subway.init(&DungeonModifiers::default());
+
+        for step_number in 1..20 {
+            subway.step(step_number);
+        }
+        assert_eq!(subway.get_early_entrance_visits(), 10.);
+        assert_eq!(subway.get_entrance_exit_probability(), 0.);
+
+        subway.step(20);
+        subway.step(21);
+        assert_eq!(subway.get_early_entrance_visits(), 10.);
+        assert_eq!(subway.get_entrance_exit_probability(), 1.);
+        assert_eq!(subway.visited[128], 11.);
+    }
+
+    #[wasm_bindgen_test]
+    fn test_jumpy_modifier() {
+        let mut subway = Subway::new();
+        for idx in 123..=127 {
+            subway.set_field(idx, Cell::Pass);
+        }
+        subway.set_field(128, Cell::Entrance);
+        subway.init(&DungeonModifiers {
+            jumpy: true,
+            ..Default::default()
+        });
+
+        // jumps are not possible on first moves
+        let (_, probs) = subway.get_movement(126, Direction::East, 4);
+        assert_eq!(probs.as_slice(), [1., 0., 0., 0., 0., 0., 0., 0.]);
+
+        let (indices, probs) = subway.get_movement(126, Direction::East, 5);
+        assert_eq!(indices[4], 124);
+        assert_eq!(probs.as_slice(), [0.8, 0., 0., 0., 0.2, 0., 0., 0.]);
+    }
+
+    #[wasm_bindgen_test]
+    fn test_reversed_modifier() {
+        // crossing: going north from 128 meets turns to the east and west
+        let mut subway = Subway::new();
+        subway.set_field(128, Cell::Entrance);
+        subway.set_field(108, Cell::Pass);
+        subway.set_field(107, Cell::Pass);
+        subway.set_field(109, Cell::Pass);
+        subway.set_field(88, Cell::Pass);
+        subway.init(&DungeonModifiers::default());
+
+        let (indices, probs) = subway.get_movement(108, Direction::South, 1);
+        assert_eq!(indices[..4], [88, 109, 128, 107]);
+        assert_eq!(probs.as_slice(), [0.85, 0.15, 0., 0., 0., 0., 0., 0.]);
+
+        subway.init(&DungeonModifiers {
+            reversed: true,
+            ..Default::default()
+        });
+        let (_, probs) = subway.get_movement(108, Direction::South, 1);
+        assert_eq!(probs.as_slice(), [0.85, 0., 0., 0.15, 0., 0., 0., 0.]);
+
+        // dead end to the north: turns take the rest
+        subway.set_field(88, Cell::Wall);
+        let (_, probs) = subway.get_movement(108, Direction::South, 1);
+        assert_eq!(probs.as_slice(), [0., 0.2, 0., 0.8, 0., 0., 0., 0.]);
+    }
+
+    /// Default rules, but signs are followed only half of the time
+    struct CarelessMovement;
+
+    impl MovementModel for CarelessMovement {
+        fn turn_probabilities(&self, walls: [bool; 4]) -> [f64; 4] {
+            GodvilleMovement.turn_probabilities(walls)
+        }
+        fn jump_probability(&self) -> f64 {
+            GodvilleMovement.jump_probability()
+        }
+        fn jump_start_move(&self) -> u32 {
+            GodvilleMovement.jump_start_move()
+        }
+        fn entrance_exit_move(&self) -> u32 {
+            GodvilleMovement.entrance_exit_move()
+        }
+        fn sign_probability(&self) -> f64 {
+            0.5
+        }
+    }
+
+    #[wasm_bindgen_test]
+    fn test_signs() {
+        // crossing: going north from 128, a sign points west
+        let mut subway = Subway::new();
+        subway.set_field(128, Cell::Entrance);
+        subway.set_field(108, Cell::Pass);
+        subway.set_field(107, Cell::Pass);
+        subway.set_field(109, Cell::Pass);
+        subway.set_field(88, Cell::Pass);
+        assert!(subway.set_sign(108, Direction::West));
+        assert!(!subway.set_sign(8, Direction::West));
+        assert!(subway.get_sign(108) == Some(Direction::West));
+        subway.init(&DungeonModifiers::default());
+
+        let (_, probs) = subway.get_movement(108, Direction::South, 1);
+        assert_eq!(probs.as_slice(), [0., 0., 0., 1., 0., 0., 0., 0.]);
+        subway.step(1);
+        assert_eq!(subway.movers.column(107).as_slice(), [0., 0., 0., 1.]);
+
+        // signs can point back
+        subway.set_sign(108, Direction::South);
+        let (_, probs) = subway.get_movement(108, Direction::South, 1);
+        assert_eq!(probs.as_slice(), [0., 0., 1., 0., 0., 0., 0., 0.]);
+
+        // signs pointing to walls are ignored
+        subway.set_sign(108, Direction::East);
+        subway.set_field(109, Cell::Wall);
+        let (_, probs) = subway.get_movement(108, Direction::South, 1);
+        assert_eq!(probs.as_slice(), [1., 0., 0., 0., 0., 0., 0., 0.]);
+        subway.clear_sign(108);
+        assert!(subway.get_sign(108).is_none());
+        subway.set_mark(108, Mark::Direction);
+        subway.set_sign(108, Direction::North);
+        subway.set_mark(108, Mark::None);
+        assert!(subway.get_sign(108).is_none());
+
+        // signs only bias movement of careless groups
+        subway.set_model(Rc::new(CarelessMovement));
+        subway.set_field(109, Cell::Pass);
+        subway.set_sign(108, Direction::West);
+        let (_, probs) = subway.get_movement(108, Direction::South, 1);
+        assert_eq!(probs.as_slice(), [0.425, 0.075, 0., 0.5, 0., 0., 0., 0.]);
+    }
+
+    #[wasm_bindgen_test]
+    fn test_double_speed_modifier() {
+        let mut subway = Subway::new();
+        subway.set_field(128, Cell::Entrance);
+        subway.set_field(127, Cell::Pass);
+        subway.set_field(126, Cell::Pass);
+        subway.set_field(125, Cell::Exit);
+        subway.init(&DungeonModifiers {
+            double_speed: true,
+            ..Default::default()
+        });
+
+        subway.step(1);
+        assert_eq!(subway.visited[127], 1.);
+        assert_eq!(subway.visited[126], 1.);
+        assert_eq!(subway.movers.column(125).as_slice(), [0., 0., 0., 1.]);
+
+        subway.step(2);
+        assert_eq!(subway.get_arrivals(125), [0., 1.]);
+        assert_eq!(subway.movers.sum(), 0.);
+    }
+
+    #[wasm_bindgen_test]
+    fn test_marks() {
+        let mut subway = Subway::new();
+        assert!(subway.set_mark(128, Mark::FinalBoss));
+        assert!(subway.set_mark(148, Mark::OtherBoss));
+        assert!(subway.set_mark(150, Mark::OtherBoss));
+        assert!(!subway.set_mark(0, Mark::Trap));
+        assert!(!subway.set_mark(400, Mark::Trap));
+
+        // marks stay when cells are redrawn
+        subway.set_field(128, Cell::Pass);
+        assert!(subway.get_mark(128) == Mark::FinalBoss);
+        assert!(subway.get_mark(400) == Mark::None);
+        assert_eq!(subway.get_mark_cells(Mark::OtherBoss), vec![148, 150]);
+
+        subway.reset();
+        assert!(subway.get_mark_cells(Mark::FinalBoss).is_empty());
+    }
+
+    #[wasm_bindgen_test]
+    fn test_custom_size() {
+        // long corridor with a turn, in a field wider than the default one
+        let mut subway = Subway::with_size(5, 32);
+        assert_eq!(subway.get_flat_size(), 160);
+        let entrance = subway.to_idx(1, 1);
+        let corner = subway.to_idx(1, 30);
+        let exit = subway.to_idx(3, 30);
+        assert_eq!(subway.to_coordinate(corner).col, 30);
+
+        subway.set_field(entrance, Cell::Entrance);
+        for col in 2..=30 {
+            subway.set_field(subway.to_idx(1, col), Cell::Pass);
+        }
+        subway.set_field(subway.to_idx(2, 30), Cell::Pass);
+        subway.set_field(exit, Cell::Exit);
+        // guard rails stay walls
+        subway.set_field(subway.to_idx(1, 31), Cell::Pass);
+        subway.set_field(subway.to_idx(4, 30), Cell::Pass);
+        assert_eq!(subway.get_field(subway.to_idx(1, 31)), Cell::Wall);
+        assert_eq!(subway.get_field(subway.to_idx(4, 30)), Cell::Wall);
+
+        subway.init(&DungeonModifiers::default());
+        for step_number in 1..=31 {
+            subway.step(step_number);
+        }
+        assert_eq!(subway.get_visited_probability(corner), 1.);
+        // most of the group turns right at the corner, the rest goes back
+        assert_eq!(subway.get_arrivals(exit)[30], 0.8);
+
+        // resizing starts over
+        subway.resize(20, 20);
+        assert_eq!(subway.get_rows(), 20);
+        assert_eq!(subway.get_last_step(), 0);
+        assert!(subway.get_visited_probabilities().iter().all(|&v| v == 0.));
+    }
+
+    #[wasm_bindgen_test]
+    fn test_run_until() {
+        let mut subway = Subway::new();
+        subway.set_field(128, Cell::Entrance);
+        subway.set_field(127, Cell::Pass);
+        subway.set_field(126, Cell::Pass);
+        subway.set_field(125, Cell::Exit);
+        subway.init(&DungeonModifiers::default());
+
+        let summary = subway.run_until(1e-9, 2);
+        assert_eq!(summary.steps, 2);
+        assert_eq!(summary.remaining, 1.);
+        assert!(!summary.converged);
+
+        let summary = subway.run_until(1e-9, 100);
+        assert_eq!(summary.steps, 1);
+        assert_eq!(summary.last_step, 3);
+        assert_eq!(summary.remaining, 0.);
+        assert!(summary.converged);
+        assert_eq!(subway.visited[125], 1.);
+
+        // nothing left to do
+        let summary = subway.run_until(1e-9, 100);
+        assert_eq!(summary.steps, 0);
+        assert_eq!(summary.last_step, 3);
+    }
+
+    /// Always turns back, entrance opens early
+    struct BouncingMovement;
+
+    impl MovementModel for BouncingMovement {
+        fn turn_probabilities(&self, walls: [bool; 4]) -> [f64; 4] {
+            [0., 0., if walls[2] { 0. } else { 1. }, 0.]
+        }
+        fn jump_probability(&self) -> f64 {
+            0.
+        }
+        fn jump_start_move(&self) -> u32 {
+            0
+        }
+        fn entrance_exit_move(&self) -> u32 {
+            2
+        }
+    }
+
+    #[wasm_bindgen_test]
+    fn test_custom_model() {
+        let mut subway = Subway::with_model(Rc::new(BouncingMovement));
+        subway.set_field(128, Cell::Entrance);
+        subway.set_field(127, Cell::Pass);
+        subway.set_field(126, Cell::Pass);
+        subway.set_field(125, Cell::Exit);
+        subway.init(&DungeonModifiers::default());
+
+        subway.step(1);
+        assert_eq!(subway.movers.column(128).as_slice(), [0., 1., 0., 0.]);
+        subway.step(2);
+        assert_eq!(subway.get_arrivals(128), [0., 1.]);
+        assert_eq!(subway.movers.sum(), 0.);
+        assert_eq!(subway.visited[125], 0.);
+    }
 }
@@ -0,0 +1,109 @@
+use wasm_bindgen::prelude::*;
+
+use crate::field::Subway;
+
+#[wasm_bindgen]
+impl Subway {
+    /// Get expected numbers of moves out of every cell, by direction
+    ///
+    /// There are 8 numbers per cell: walking north, east, south and west,
+    /// then jumping in the same directions.
+    pub fn get_flows(&self) -> Vec<f64> {
+        self.flows.as_slice().to_vec()
+    }
+
+    /// Get expected number of moves from cell `from` to cell `to`
+    ///
+    /// Cells are either neighbours, or two cells apart in a line for jumps.
+    /// Any other cells have no moves between them.
+    pub fn get_edge_flow(&self, from: usize, to: usize) -> f64 {
+        match self.flow_slot(from, to) {
+            Some(slot) if from < self.field.len() => self.flows[(slot, from)],
+            _ => 0.,
+        }
+    }
+}
+
+impl Subway {
+    /// Index of the flows of moves from cell `from` to cell `to`
+    fn flow_slot(&self, from: usize, to: usize) -> Option<usize> {
+        let cols = self.cols;
+        let offsets = [
+            (to + cols == from, 0),
+            (from + 1 == to, 1),
+            (from + cols == to, 2),
+            (to + 1 == from, 3),
+            (to + 2 * cols == from, 4),
+            (from + 2 == to, 5),
+            (from + 2 * cols == to, 6),
+            (to + 2 == from, 7),
+        ];
+        offsets
+            .iter()
+            .find(|(matches, _)| *matches)
+            .map(|&(_, slot)| slot)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::field::Cell;
+    use crate::movement::DungeonModifiers;
+    use wasm_bindgen_test::*;
+
+    #[wasm_bindgen_test]
+    fn test_freeway_flows() {
+        let mut subway = Subway::new();
+        subway.set_field(128, Cell::Entrance);
+        subway.set_field(127, Cell::Pass);
+        subway.set_field(126, Cell::Pass);
+        subway.set_field(125, Cell::Exit);
+        subway.init(&DungeonModifiers::default());
+        for step_number in 1..=5 {
+            subway.step(step_number);
+        }
+
+        assert_eq!(subway.get_edge_flow(128, 127), 1.);
+        assert_eq!(subway.get_edge_flow(127, 126), 1.);
+        assert_eq!(subway.get_edge_flow(126, 125), 1.);
+        assert_eq!(subway.get_edge_flow(127, 128), 0.);
+        assert_eq!(subway.get_edge_flow(125, 124), 0.);
+        assert_eq!(subway.get_edge_flow(128, 100), 0.);
+        assert_eq!(subway.get_flows()[127 * 8 + 3], 1.);
+    }
+
+    #[wasm_bindgen_test]
+    fn test_flows_match_visits() {
+        // loop around the center, with jumps across it
+        let mut subway = Subway::loop_field();
+        subway.init(&DungeonModifiers {
+            jumpy: true,
+            ..Default::default()
+        });
+        for step_number in 1..=30 {
+            subway.step(step_number);
+        }
+        assert!(subway.get_edge_flow(148, 108) > 0.);
+
+        // every move into a cell is either counted as a visit, or still on the way
+        let flat_size = subway.get_flat_size();
+        let mut inflow = vec![0.; flat_size];
+        for (to, flow) in inflow.iter_mut().enumerate() {
+            for from in to.saturating_sub(40)..(to + 41).min(flat_size) {
+                *flow += subway.get_edge_flow(from, to);
+            }
+        }
+        for (idx, &flow) in inflow.iter().enumerate() {
+            let start = if idx == 128 { 1. } else { 0. };
+            let expected = subway.visited[idx] + subway.movers.column(idx).sum() - start;
+            assert!(
+                (flow - expected).abs() < 1e-9,
+                "{}: {} != {}",
+                idx,
+                flow,
+                expected
+            );
+        }
+    }
+}
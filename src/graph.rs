@@ -0,0 +1,236 @@
+use std::collections::VecDeque;
+
+use wasm_bindgen::prelude::*;
+
+use crate::field::{Cell, Direction, Subway, DIRECTIONS};
+
+/// Earliest arrival of a group at a search state
+#[derive(Clone, Copy)]
+struct Arrival {
+    /// number of moves since the start
+    moves: u32,
+    /// search state the group came from
+    previous: Option<usize>,
+}
+
+/// Result of the search of earliest arrivals
+struct ArrivalSearch {
+    /// number of move counts distinguished by the search
+    timeline: u32,
+    /// arrivals at search states, `timeline` of them per cell and heading
+    states: Vec<Option<Arrival>>,
+}
+
+impl ArrivalSearch {
+    /// Search states of cell `idx`
+    fn cell_states(&self, idx: usize) -> std::ops::Range<usize> {
+        let timeline = self.timeline as usize;
+        idx * 4 * timeline..(idx + 1) * 4 * timeline
+    }
+
+    /// Cell of search state `state`
+    fn cell_of(&self, state: usize) -> usize {
+        state / (4 * self.timeline as usize)
+    }
+
+    /// Get the earliest arrival at cell `idx`, as a search state
+    fn first_arrival(&self, idx: usize) -> Option<(usize, Arrival)> {
+        self.cell_states(idx)
+            .filter_map(|state| self.states[state].map(|arrival| (state, arrival)))
+            .min_by_key(|(_, arrival)| arrival.moves)
+    }
+}
+
+impl Subway {
+    /// Find walking distances from `starts` to every cell, in moves
+    ///
+    /// Groups start from the entrance as in `init`. From other cells they start
+    /// in any direction, with the rules of later moves. Routes follow moves
+    /// of the movement model that have non-zero probability, and do not
+    /// go through exits. Cells not reachable from any of `starts` get no distance.
+    pub(crate) fn distances_from(&self, starts: &[usize], jumps: bool) -> Vec<Option<u32>> {
+        let search = self.search_arrivals(starts, jumps);
+        (0..self.field.len())
+            .map(|idx| search.first_arrival(idx).map(|(_, arrival)| arrival.moves))
+            .collect()
+    }
+
+    /// Search earliest arrivals at every cell from `starts`
+    ///
+    /// Turns depend on the heading, jumps (if enabled) only become possible
+    /// after a few moves, and the entrance turns into an exit later on,
+    /// so the search goes over (cell, heading, move) states, with moves
+    /// after the rules stop changing all being the same.
+    fn search_arrivals(&self, starts: &[usize], jumps: bool) -> ArrivalSearch {
+        let mut rules = self.clone();
+        rules.modifiers.jumpy = jumps;
+        let moves_per_step = self.modifiers.moves_per_step();
+        let step_of = |moves: u32| moves.saturating_sub(1) / moves_per_step + 1;
+        let timeline = self.model.stationary_move() * moves_per_step + 1;
+        let mut search = ArrivalSearch {
+            timeline,
+            states: vec![None; self.field.len() * 4 * timeline as usize],
+        };
+        let state_of = |idx: usize, heading: usize, time: u32| {
+            (idx * 4 + heading) * timeline as usize + time.min(timeline - 1) as usize
+        };
+
+        // queue of (cell, heading, move count) states
+        let mut queue = VecDeque::new();
+        for &start in starts {
+            if start >= self.field.len() {
+                continue;
+            }
+            let headings = if self.field[start] == Cell::Entrance {
+                // same as in `init`: the group leaves the entrance facing away from it
+                vec![(Direction::North as usize, 0)]
+            } else {
+                (0..4).map(|heading| (heading, timeline - 1)).collect()
+            };
+            for (heading, time) in headings {
+                let state = state_of(start, heading, time);
+                if search.states[state].is_none() {
+                    search.states[state] = Some(Arrival {
+                        moves: 0,
+                        previous: None,
+                    });
+                    queue.push_back((start, heading, time));
+                }
+            }
+        }
+        while let Some((idx, heading, time)) = queue.pop_front() {
+            // groups leave the field here
+            if time > 0 && self.is_exit(idx, step_of(time)) {
+                continue;
+            }
+            let state = state_of(idx, heading, time);
+            let moves = search.states[state].map_or(0, |arrival| arrival.moves);
+            // the first move from the entrance follows its own rules
+            let move_count = if time == 0 { 0 } else { step_of(time) };
+            let next_time = (time + 1).min(timeline - 1);
+            for (_, next_idx, next_heading, _) in
+                rules.mover_moves(idx, DIRECTIONS[heading], move_count)
+            {
+                let next_state = state_of(next_idx, next_heading, next_time);
+                if search.states[next_state].is_none() {
+                    search.states[next_state] = Some(Arrival {
+                        moves: moves + 1,
+                        previous: Some(state),
+                    });
+                    queue.push_back((next_idx, next_heading, next_time));
+                }
+            }
+        }
+        search
+    }
+
+    /// Cells of the entrance, where searches from the entrance start
+    fn entrances(&self) -> Vec<usize> {
+        (0..self.field.len())
+            .filter(|&idx| self.field[idx] == Cell::Entrance)
+            .collect()
+    }
+}
+
+#[wasm_bindgen]
+impl Subway {
+    /// Get cells reachable from the entrance
+    ///
+    /// Like other path searches, this ignores how probable the moves are and only
+    /// tells where a group can get at all. With `jumps`, the dungeon is taken
+    /// as jumpy, with jumps possible after a few first moves.
+    pub fn get_reachable(&self, jumps: bool) -> Vec<usize> {
+        self.distances_from(&self.entrances(), jumps)
+            .iter()
+            .enumerate()
+            .filter_map(|(idx, distance)| distance.map(|_| idx))
+            .collect()
+    }
+
+    /// Get the earliest step a group can visit each cell at, or -1 for unreachable cells
+    ///
+    /// The entrance is visited at step 0.
+    pub fn get_distances(&self, jumps: bool) -> Vec<i32> {
+        let moves_per_step = self.modifiers.moves_per_step();
+        self.distances_from(&self.entrances(), jumps)
+            .iter()
+            .map(|distance| match distance {
+                Some(moves) => moves.div_ceil(moves_per_step) as i32,
+                None => -1,
+            })
+            .collect()
+    }
+
+    /// Get the shortest route from the entrance to cell `exit`
+    ///
+    /// Returns cells of the route starting with the entrance,
+    /// or nothing if `exit` is not reachable.
+    pub fn get_shortest_path(&self, exit: usize, jumps: bool) -> Vec<usize> {
+        let mut cells = Vec::new();
+        if exit >= self.field.len() {
+            return cells;
+        }
+        let search = self.search_arrivals(&self.entrances(), jumps);
+        let mut current = search.first_arrival(exit).map(|(state, _)| state);
+        while let Some(state) = current {
+            cells.push(search.cell_of(state));
+            current = search.states[state].and_then(|arrival| arrival.previous);
+        }
+        cells.reverse();
+        cells
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::movement::DungeonModifiers;
+    use wasm_bindgen_test::*;
+
+    #[wasm_bindgen_test]
+    fn test_shortest_paths() {
+        // corridor to the exit blocked by a wall at 126, with a detour around it,
+        // and a closed cell at 89 only reachable by jumping
+        let mut subway = Subway::new();
+        subway.set_field(130, Cell::Entrance);
+        for idx in [129, 128, 127, 107, 87, 86, 85, 105, 125, 89] {
+            subway.set_field(idx, Cell::Pass);
+        }
+        subway.set_field(124, Cell::Exit);
+        subway.init(&DungeonModifiers::default());
+
+        assert_eq!(
+            subway.get_shortest_path(124, false),
+            vec![130, 129, 128, 127, 107, 87, 86, 85, 105, 125, 124]
+        );
+        let distances = subway.get_distances(false);
+        assert_eq!(distances[130], 0);
+        assert_eq!(distances[124], 10);
+        assert_eq!(distances[89], -1);
+        assert!(!subway.get_reachable(false).contains(&89));
+
+        // jumps become possible from step 5, too late to jump over the wall
+        // at 127, but in time to cut the corner at 87, or to jump up
+        // after turning back at the corner at 127
+        let distances = subway.get_distances(true);
+        assert_eq!(distances[124], 8);
+        assert_eq!(distances[89], 6);
+        assert!(subway.get_reachable(true).contains(&89));
+        assert_eq!(
+            subway.get_shortest_path(124, true),
+            vec![130, 129, 128, 127, 107, 87, 85, 125, 124]
+        );
+        assert_eq!(
+            subway.get_shortest_path(89, true),
+            vec![130, 129, 128, 127, 128, 129, 89]
+        );
+        assert!(subway.get_shortest_path(89, false).is_empty());
+
+        // two moves per step
+        subway.init(&DungeonModifiers {
+            double_speed: true,
+            ..Default::default()
+        });
+        assert_eq!(subway.get_distances(false)[124], 5);
+    }
+}
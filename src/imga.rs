@@ -9,7 +9,7 @@ use wasm_bindgen::prelude::*;
 
 use crate::brief::{center_mass, Brief, get_brief_vectors};
 use crate::features::FEATURE_DATA;
-use crate::field::{Cell, Coordinate, Subway};
+use crate::field::{Cell, Coordinate, Direction, ExitKind, Mark, Subway};
 
 /// Threshold for closeness to existing feature data
 ///
@@ -25,7 +25,7 @@ pub struct ImageProcessor {
 }
 
 #[wasm_bindgen]
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Default)]
 pub struct Grid {
     pub size: usize,
     pub row_offset: usize,
@@ -34,66 +34,58 @@ pub struct Grid {
     pub col_count: usize,
 }
 
-#[wasm_bindgen]
-#[derive(Copy, Clone, Debug)]
-pub enum Mark {
-    None = 0,
-    Wall = 1,
-    Entrance = 2,
-    Treasury = 3,
-    Subtreasury = 4,
-    FinalBoss = 5,
-    OtherBoss = 6,
-    Ladder = 7,
-    Trap = 8,
-    Luck = 9,
-    RaiseWall = 10,
-    Direction = 11,
-    Scarecrow = 12,
-    Fountain = 13,
-}
-
 /// Encapsulates detected maze for passing around
 #[wasm_bindgen]
 pub struct Maze {
     grid: Grid,
     cells: Vec<Cell>,
     marks: Vec<Mark>,
+    /// directions of detected signs
+    signs: Vec<Option<Direction>>,
 }
 
 #[wasm_bindgen]
 impl Maze {
-    /// Apply detected maze to the subway field
-    pub fn apply_to_subway(&self, subway: &mut Subway) {
-        let subway_row_offset = (crate::field::SIZE_Y - self.grid.row_count) / 2;
-        let subway_col_offset = (crate::field::SIZE_X - self.grid.col_count) / 2;
+    /// Apply detected maze (cells, marks and signs) to the subway field
+    ///
+    /// The maze is centred in the field. Returns false, leaving the field
+    /// as it is, if the field is too small to hold the maze inside guard rails.
+    pub fn apply_to_subway(&self, subway: &mut Subway) -> bool {
+        if subway.get_rows() < self.grid.row_count + 2 || subway.get_cols() < self.grid.col_count + 2
+        {
+            return false;
+        }
+        let (subway_row_offset, subway_col_offset) = self.offsets(subway);
 
         subway.reset();
         for row in 0..self.grid.row_count {
             for col in 0..self.grid.col_count {
                 let grid_idx = row * self.grid.col_count + col;
-                subway.set_field(
-                    Subway::to_idx(row + subway_row_offset, col + subway_col_offset),
-                    match self.marks[grid_idx] {
-                        Mark::Entrance => Cell::Entrance,
-                        Mark::Treasury => Cell::Exit,
-                        _ => self.cells[grid_idx],
-                    },
-                )
+                let idx = subway.to_idx(row + subway_row_offset, col + subway_col_offset);
+                match self.marks[grid_idx] {
+                    Mark::Entrance => subway.set_field(idx, Cell::Entrance),
+                    Mark::Treasury => subway.set_exit(idx, ExitKind::Treasury),
+                    Mark::Subtreasury => subway.set_exit(idx, ExitKind::Subtreasury),
+                    _ => subway.set_field(idx, self.cells[grid_idx]),
+                };
+                subway.set_mark(idx, self.marks[grid_idx]);
+                if let Some(direction) = self.signs[grid_idx] {
+                    subway.set_sign(idx, direction);
+                }
             }
         }
+        true
     }
 
     /// Get a mark at a specified location
     ///
     /// Location relative to larger Subway, which is offseted by maze size
-    pub fn get_mark(&self, idx: usize) -> Mark {
-        let subway_row_offset = (crate::field::SIZE_Y - self.grid.row_count) / 2;
-        let subway_col_offset = (crate::field::SIZE_X - self.grid.col_count) / 2;
+    pub fn get_mark(&self, subway: &Subway, idx: usize) -> Mark {
+        let (subway_row_offset, subway_col_offset) = self.offsets(subway);
 
         // requested coordinate can lie outside of the detected maze, so
         // return nothing
-        let Coordinate { row, col } = Subway::from_idx(idx);
+        let Coordinate { row, col } = subway.to_coordinate(idx);
         if row < subway_row_offset
             || col < subway_col_offset
             || row >= (self.grid.row_count + subway_row_offset)
@@ -116,6 +108,44 @@ impl Maze {
     }
 }
 
+impl Maze {
+    /// Offsets of the maze centred in the `subway` field, as (rows, columns)
+    fn offsets(&self, subway: &Subway) -> (usize, usize) {
+        (
+            subway.get_rows().saturating_sub(self.grid.row_count) / 2,
+            subway.get_cols().saturating_sub(self.grid.col_count) / 2,
+        )
+    }
+}
+
+/// Find direction of an arrow icon, drawn dark on a light background
+///
+/// Arrow heads have more dark pixels than shafts, so the arrow points
+/// from the image center to the centroid of dark pixels.
+fn sign_direction(img: &GrayImage) -> Direction {
+    let pixel_count = (img.width() * img.height()).max(1) as f64;
+    let mean = img.pixels().map(|pixel| pixel.0[0] as f64).sum::<f64>() / pixel_count;
+    let center_x = (img.width() as f64 - 1.) / 2.;
+    let center_y = (img.height() as f64 - 1.) / 2.;
+    let (mut shift_x, mut shift_y) = (0., 0.);
+    for (x, y, pixel) in img.enumerate_pixels() {
+        let darkness = (mean - pixel.0[0] as f64).max(0.);
+        shift_x += darkness * (x as f64 - center_x);
+        shift_y += darkness * (y as f64 - center_y);
+    }
+    if shift_x.abs() > shift_y.abs() {
+        if shift_x > 0. {
+            Direction::East
+        } else {
+            Direction::West
+        }
+    } else if shift_y > 0. {
+        Direction::South
+    } else {
+        Direction::North
+    }
+}
+
 impl std::fmt::Display for Grid {
     fn fmt(&self, formatter: &mut std::fmt::Formatter) -> Result<(), std::fmt::Error> {
         formatter.write_str(&format!(
@@ -125,18 +155,6 @@ impl std::fmt::Display for Grid {
     }
 }
 
-impl Default for Grid {
-    fn default() -> Self {
-        Self {
-            size: 0,
-            row_count: 0,
-            row_offset: 0,
-            col_count: 0,
-            col_offset: 0,
-        }
-    }
-}
-
 struct GridPeriod {
     period: usize,
     offset: usize,
@@ -481,11 +499,13 @@ impl ImageProcessor {
     pub fn detect_maze(&self, grid: &Grid) -> Maze {
         let mut cells = Vec::with_capacity(grid.col_count * grid.row_count);
         let mut marks = Vec::with_capacity(grid.col_count * grid.row_count);
+        let mut signs = vec![None; grid.col_count * grid.row_count];
         if grid.size == 0 {
             return Maze {
                 grid: *grid,
                 cells,
                 marks,
+                signs,
             };
         }
 
@@ -541,7 +561,7 @@ impl ImageProcessor {
                                 .map::<u8, fn(u32) -> u8>(|value| (value / 3) as u8)
                                 .reshape_generic(
                                     Const::<1>,
-                                    Dynamic::new((cell_size * cell_size) as usize),
+                                    Dynamic::new(cell_size * cell_size),
                                 )
                                 .data
                                 .into(),
@@ -550,8 +570,8 @@ impl ImageProcessor {
                         if dark_mode {
                             imageops::invert(&mut cell_img);
                         }
-                        cell_img = imageops::resize(&cell_img, 8, 8, imageops::CatmullRom);
-                        let feature_vector = FeatureVector::from_image(&cell_img);
+                        let icon_img = imageops::resize(&cell_img, 8, 8, imageops::CatmullRom);
+                        let feature_vector = FeatureVector::from_image(&icon_img);
                         let (detected_mark, similarity) = self.get_closest_feature(&feature_vector);
                         if self.debug_output {
                             web_sys::console::log_1(
@@ -580,6 +600,10 @@ impl ImageProcessor {
                                     if let Some(last) = marks.last_mut() {
                                         *last = detected_mark;
                                     }
+                                    if detected_mark == Mark::Direction {
+                                        // full size icon, arrow heads blur when resized
+                                        signs[cells.len() - 1] = Some(sign_direction(&cell_img));
+                                    }
                                 }
                             }
                         }
@@ -600,6 +624,7 @@ impl ImageProcessor {
             grid: *grid,
             cells,
             marks,
+            signs,
         }
     }
 
@@ -639,6 +664,7 @@ impl ImageProcessor {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::brief::OFFSETS_INT;
     use wasm_bindgen_test::*;
 
     #[wasm_bindgen_test]
@@ -683,4 +709,65 @@ mod tests {
             vec![2, 2, 2, 255, 6, 6, 6, 255, 10, 10, 10, 255, 14, 14, 14, 255]
         );
     }
+
+    #[wasm_bindgen_test]
+    fn arrow_direction() {
+        // arrow pointing east: a shaft with a triangular head
+        let mut img = GrayImage::from_pixel(15, 15, Luma([230]));
+        for x in 2..9 {
+            img.put_pixel(x, 7, Luma([20]));
+        }
+        for x in 9..14u32 {
+            let half_height = 13 - x;
+            for y in 7 - half_height..=7 + half_height {
+                img.put_pixel(x, y, Luma([20]));
+            }
+        }
+        assert_eq!(sign_direction(&img), Direction::East);
+        assert_eq!(sign_direction(&imageops::rotate90(&img)), Direction::South);
+        assert_eq!(sign_direction(&imageops::rotate180(&img)), Direction::West);
+        assert_eq!(sign_direction(&imageops::rotate270(&img)), Direction::North);
+    }
+
+    /// Rough icon restored from its BRIEF vectors: the darker pixel
+    /// of every compared pair gets darker, the other one gets lighter
+    fn icon_from_data(data: &FeatureData) -> GrayImage {
+        let vector = FeatureVector::from_data(data);
+        let mut darkness = [[0i32; 24]; 24];
+        for brief in vector.0.iter() {
+            // pixels wrap around, as in `get_brief_vectors`
+            let pixel = |(dx, dy): (i32, i32)| {
+                ((brief.x + dx).rem_euclid(24) as usize, (brief.y + dy).rem_euclid(24) as usize)
+            };
+            for (&(first, second), brighter) in OFFSETS_INT.iter().zip(brief.b.iter().by_vals()) {
+                let (dark, light) = if brighter {
+                    (pixel(second), pixel(first))
+                } else {
+                    (pixel(first), pixel(second))
+                };
+                darkness[dark.1][dark.0] += 1;
+                darkness[light.1][light.0] -= 1;
+            }
+        }
+        GrayImage::from_fn(24, 24, |x, y| {
+            Luma([(128 - 20 * darkness[y as usize][x as usize]).clamp(0, 255) as u8])
+        })
+    }
+
+    #[wasm_bindgen_test]
+    fn known_sign_direction() {
+        // both known sign icons point east
+        let icons: Vec<GrayImage> = FEATURE_DATA
+            .iter()
+            .filter(|(_, mark)| *mark == Mark::Direction)
+            .map(|(data, _)| icon_from_data(data))
+            .collect();
+        assert_eq!(icons.len(), 2);
+        for img in icons {
+            assert_eq!(sign_direction(&img), Direction::East);
+            assert_eq!(sign_direction(&imageops::rotate90(&img)), Direction::South);
+            assert_eq!(sign_direction(&imageops::rotate180(&img)), Direction::West);
+            assert_eq!(sign_direction(&imageops::rotate270(&img)), Direction::North);
+        }
+    }
 }
@@ -0,0 +1,160 @@
+use wasm_bindgen::prelude::*;
+
+use crate::field::Subway;
+
+/// Largest relative difference of in and out mass of a balanced step
+const BALANCE_TOLERANCE: f64 = 1e-9;
+
+/// Mass balance of a single step
+///
+/// Movers mass at the start of a step ends up either in flight,
+/// absorbed by exits or the entrance, stopped by marks, taken to other
+/// floors of a dungeon by ladders, or lost.
+/// Mass is lost in cells movers have no way out of (e.g. cells enclosed by walls).
+#[wasm_bindgen]
+#[derive(Clone, Copy, Default)]
+pub struct LedgerEntry {
+    /// step number
+    pub step: u32,
+    /// movers mass at the start of the step
+    pub start: f64,
+    /// movers mass still inside the field after the step (including delayed groups)
+    pub in_flight: f64,
+    /// mass leaving the field through exits
+    pub exits: f64,
+    /// mass leaving the field through the entrance
+    pub entrance: f64,
+    /// mass disappearing without leaving the field
+    pub lost: f64,
+    /// mass stopped by effects of marks
+    pub stopped: f64,
+    /// mass taken to other floors by ladders, less mass brought from them
+    pub transferred: f64,
+}
+
+#[wasm_bindgen]
+impl LedgerEntry {
+    /// Difference of the mass at the start of the step and where it went
+    pub fn imbalance(&self) -> f64 {
+        self.start
+            - (self.in_flight
+                + self.exits
+                + self.entrance
+                + self.lost
+                + self.stopped
+                + self.transferred)
+    }
+
+    /// Tell if all the mass of the step is accounted for
+    pub fn is_balanced(&self) -> bool {
+        self.imbalance().abs() <= BALANCE_TOLERANCE * self.start.max(1.)
+    }
+}
+
+#[wasm_bindgen]
+impl Subway {
+    /// Get mass balance of each performed step
+    ///
+    /// Element `k` describes step `k + 1`.
+    pub fn get_ledger(&self) -> Vec<LedgerEntry> {
+        self.ledger.clone()
+    }
+
+    /// Get total mass lost so far
+    pub fn get_lost_mass(&self) -> f64 {
+        self.ledger.iter().map(|entry| entry.lost).sum()
+    }
+
+    /// Get total mass stopped by effects of marks so far
+    pub fn get_stopped_mass(&self) -> f64 {
+        self.ledger.iter().map(|entry| entry.stopped).sum()
+    }
+
+    /// Turn checking of the mass balance after each step on or off
+    ///
+    /// Unbalanced steps are collected (see `get_unbalanced_steps`)
+    /// and reported to the console.
+    pub fn set_ledger_checks(&mut self, enabled: bool) {
+        self.ledger_checks = enabled;
+    }
+
+    /// Get numbers of steps that failed the mass balance check
+    pub fn get_unbalanced_steps(&self) -> Vec<u32> {
+        self.unbalanced_steps.clone()
+    }
+}
+
+impl Subway {
+    /// Store balance of a performed step, checking it if asked to
+    pub(crate) fn record_ledger(&mut self, entry: LedgerEntry) {
+        let step_pos = entry.step.max(1) as usize - 1;
+        if self.ledger.len() <= step_pos {
+            self.ledger.resize(step_pos + 1, LedgerEntry::default());
+        }
+        self.ledger[step_pos] = entry;
+
+        if self.ledger_checks && !entry.is_balanced() {
+            #[cfg(target_arch = "wasm32")]
+            web_sys::console::warn_1(
+                &format!(
+                    "Step {} is unbalanced by {:e}",
+                    entry.step,
+                    entry.imbalance()
+                )
+                .into(),
+            );
+            if !self.unbalanced_steps.contains(&entry.step) {
+                self.unbalanced_steps.push(entry.step);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::field::Cell;
+    use crate::movement::DungeonModifiers;
+    use wasm_bindgen_test::*;
+
+    #[wasm_bindgen_test]
+    fn test_ledger_balance() {
+        // corridor with a closed cell at its end, reachable by jumping only
+        let mut subway = Subway::new();
+        subway.set_field(122, Cell::Pass);
+        for idx in 124..=129 {
+            subway.set_field(idx, Cell::Pass);
+        }
+        subway.set_field(130, Cell::Entrance);
+        subway.set_field(150, Cell::Pass);
+        subway.set_field(170, Cell::Exit);
+        subway.init(&DungeonModifiers {
+            jumpy: true,
+            ..Default::default()
+        });
+        subway.set_ledger_checks(true);
+        subway.run_until(1e-12, 1000);
+
+        let ledger = subway.get_ledger();
+        assert_eq!(ledger.len(), subway.get_last_step() as usize);
+        assert!(ledger.iter().all(|entry| entry.is_balanced()));
+        assert!(subway.get_unbalanced_steps().is_empty());
+        assert_eq!(ledger[0].start, 1.);
+        assert_eq!(ledger[0].lost, 0.);
+
+        // mass jumping into the closed cell is lost
+        let lost = subway.get_lost_mass();
+        assert!(lost > 0.);
+        let exits: f64 = ledger.iter().map(|entry| entry.exits).sum();
+        let entrance: f64 = ledger.iter().map(|entry| entry.entrance).sum();
+        assert!((exits + entrance + lost - 1.).abs() < 1e-9);
+        assert!((entrance - subway.get_entrance_exit_probability()).abs() < 1e-12);
+
+        // checks flag a step that does not add up
+        let mut entry = ledger[5];
+        entry.step = subway.get_last_step() + 1;
+        entry.in_flight += 0.1;
+        subway.record_ledger(entry);
+        assert_eq!(subway.get_unbalanced_steps(), vec![entry.step]);
+    }
+}
@@ -2,3 +2,17 @@ mod field;
 mod imga;
 mod brief;
 mod features;
+mod absorption;
+mod movement;
+mod probe;
+mod exact;
+mod snapshot;
+mod ledger;
+mod transitions;
+mod simulation;
+mod paths;
+mod flows;
+mod graph;
+mod validation;
+mod effects;
+mod dungeon;
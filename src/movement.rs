@@ -0,0 +1,117 @@
+use wasm_bindgen::prelude::*;
+
+/// Movement rules of a group in the dungeon
+///
+/// Directions are relative to the movement: forward, right, back and left
+/// (as seen when looking from south).
+pub trait MovementModel {
+    /// Probabilities to move in relative directions, given walls in these directions
+    fn turn_probabilities(&self, walls: [bool; 4]) -> [f64; 4];
+
+    /// Probabilities to leave the entrance on the first move, given walls around it
+    fn entrance_probabilities(&self, walls: [bool; 4]) -> [f64; 4] {
+        // equally random
+        let num_freeways = walls.iter().filter(|&&x| !x).count();
+        let prob = 1.0 / num_freeways as f64;
+        walls.map(|wall| if wall { 0. } else { prob })
+    }
+
+    /// Probability to jump over a cell instead of walking (in jumpy dungeons)
+    fn jump_probability(&self) -> f64;
+
+    /// Probability to go where the sign of a cell points, instead of following other rules
+    ///
+    /// By default signs are always followed unless a wall is in the way: groups never
+    /// jump from a cell with a sign, and go where it points even if that is back
+    /// where they came from.
+    /// Models with lower probabilities mix the sign in with the other moves.
+    fn sign_probability(&self) -> f64 {
+        1.
+    }
+
+    /// Move from which jumps become possible
+    fn jump_start_move(&self) -> u32;
+
+    /// Move from which the entrance works as an exit
+    fn entrance_exit_move(&self) -> u32;
+
+    /// Move from which the rules no longer depend on the move count
+    fn stationary_move(&self) -> u32 {
+        self.jump_start_move().max(self.entrance_exit_move())
+    }
+}
+
+/// Default movement rules, as observed in the game
+#[derive(Clone, Copy, Default)]
+pub struct GodvilleMovement;
+
+impl MovementModel for GodvilleMovement {
+    fn turn_probabilities(&self, walls: [bool; 4]) -> [f64; 4] {
+        match walls {
+            [true, true, true, true] => [0., 0., 0., 0.],
+            [true, true, true, false] => [0., 0., 0., 1.],
+            [true, true, false, true] => [0., 0., 1., 0.],
+            [true, false, true, true] => [0., 1., 0., 0.],
+            [false, true, true, true] => [1., 0., 0., 0.],
+            [true, true, _, false] => [0., 0., 0., 1.],
+            [true, false, _, true] => [0., 0.8, 0.2, 0.],
+            [true, false, _, false] => [0., 0.8, 0., 0.2],
+            [false, true, _, _] => [1., 0., 0., 0.],
+            [false, false, _, _] => [0.85, 0.15, 0., 0.],
+        }
+    }
+
+    fn jump_probability(&self) -> f64 {
+        0.2
+    }
+
+    fn jump_start_move(&self) -> u32 {
+        5
+    }
+
+    fn entrance_exit_move(&self) -> u32 {
+        20
+    }
+}
+
+/// Dungeon modifiers that change movement of the group
+#[wasm_bindgen]
+#[derive(Clone, Copy, Default, PartialEq, Eq)]
+pub struct DungeonModifiers {
+    /// jump moves become possible after a few first moves
+    pub jumpy: bool,
+    /// group makes two moves per step
+    pub double_speed: bool,
+    /// turning preferences are mirrored: left turns instead of right ones
+    pub reversed: bool,
+}
+
+#[wasm_bindgen]
+impl DungeonModifiers {
+    #[wasm_bindgen(constructor)]
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl DungeonModifiers {
+    /// Number of moves the group makes in a single step
+    pub fn moves_per_step(&self) -> u32 {
+        if self.double_speed {
+            2
+        } else {
+            1
+        }
+    }
+
+    /// Movement probabilities of the `model` in relative directions, with modifiers applied
+    pub fn turn_probabilities(&self, model: &dyn MovementModel, walls: [bool; 4]) -> [f64; 4] {
+        if self.reversed {
+            // look at the mirrored field and mirror the result back
+            let probs = model.turn_probabilities([walls[0], walls[3], walls[2], walls[1]]);
+            [probs[0], probs[3], probs[2], probs[1]]
+        } else {
+            model.turn_probabilities(walls)
+        }
+    }
+}
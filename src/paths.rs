@@ -0,0 +1,214 @@
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashMap};
+
+use wasm_bindgen::prelude::*;
+
+use crate::field::{Cell, Direction, Subway, DIRECTIONS};
+
+/// Complete route of a group from the entrance to an exit
+#[wasm_bindgen]
+#[derive(Clone)]
+pub struct RankedPath {
+    /// probability of the group taking exactly this route
+    pub probability: f64,
+    /// exit cell index
+    pub exit: usize,
+    cells: Vec<usize>,
+}
+
+#[wasm_bindgen]
+impl RankedPath {
+    /// Get cells of the route, starting with the entrance and ending with the exit
+    pub fn get_cells(&self) -> Vec<usize> {
+        self.cells.clone()
+    }
+}
+
+/// Cell of a route, linked to the previous one
+struct Node {
+    idx: usize,
+    heading: usize,
+    previous: Option<usize>,
+}
+
+/// Partial route in the search queue, ordered by probability
+struct Candidate {
+    probability: f64,
+    /// node of the last cell of the route
+    node: usize,
+    /// number of moves done
+    moves: u32,
+}
+
+impl PartialEq for Candidate {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Candidate {}
+
+impl PartialOrd for Candidate {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Candidate {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.probability.total_cmp(&other.probability)
+    }
+}
+
+#[wasm_bindgen]
+impl Subway {
+    /// Find `k` most probable routes from the entrance to every exit (and the entrance)
+    ///
+    /// Routes are searched over (cell, heading, step) states in order of decreasing
+    /// probability, so loops are allowed. Results are grouped by exit, the most
+    /// probable routes first. Mark effects are not followed, so fields with them
+    /// have no routes.
+    pub fn top_paths(&self, k: usize) -> Vec<RankedPath> {
+        let mut results: Vec<RankedPath> = Vec::new();
+        if self.has_mark_effects() {
+            return results;
+        }
+        let entrance = match self.field.iter().position(|&cell| cell == Cell::Entrance) {
+            Some(entrance) => entrance,
+            None => return results,
+        };
+        if k == 0 {
+            return results;
+        }
+        let moves_per_step = self.modifiers.moves_per_step();
+        // rules no longer change after this many moves, so later states are the same
+        let stationary_moves = self.model.stationary_move() * moves_per_step;
+
+        // routes are kept as a tree of nodes, starting with the entrance
+        let mut nodes = vec![Node {
+            idx: entrance,
+            heading: Direction::North as usize,
+            previous: None,
+        }];
+        let mut queue = BinaryHeap::new();
+        for (next_idx, next_heading, prob) in self.mover_transitions(entrance, Direction::North, 0)
+        {
+            nodes.push(Node {
+                idx: next_idx,
+                heading: next_heading,
+                previous: Some(0),
+            });
+            queue.push(Candidate {
+                probability: prob,
+                node: nodes.len() - 1,
+                moves: 0,
+            });
+        }
+
+        // every state is expanded at most `k` times: a route among the `k` best ones
+        // to any exit only goes through the `k` best routes to each of its states
+        let mut expanded: HashMap<(usize, usize, u32), usize> = HashMap::new();
+        let mut found: HashMap<usize, usize> = HashMap::new();
+        while let Some(Candidate {
+            probability,
+            node,
+            moves,
+        }) = queue.pop()
+        {
+            let (idx, heading) = (nodes[node].idx, nodes[node].heading);
+            let step_number = moves / moves_per_step + 1;
+            if self.is_exit(idx, step_number) {
+                let count = found.entry(idx).or_insert(0);
+                if *count < k {
+                    *count += 1;
+                    results.push(RankedPath {
+                        probability,
+                        exit: idx,
+                        cells: route_cells(&nodes, node),
+                    });
+                }
+                continue;
+            }
+
+            let times = expanded
+                .entry((idx, heading, moves.min(stationary_moves)))
+                .or_insert(0);
+            if *times >= k {
+                continue;
+            }
+            *times += 1;
+            for (next_idx, next_heading, prob) in
+                self.mover_transitions(idx, DIRECTIONS[heading], step_number)
+            {
+                nodes.push(Node {
+                    idx: next_idx,
+                    heading: next_heading,
+                    previous: Some(node),
+                });
+                queue.push(Candidate {
+                    probability: probability * prob,
+                    node: nodes.len() - 1,
+                    moves: moves + 1,
+                });
+            }
+        }
+
+        // group by exit, keeping the order of exits on the field
+        results.sort_by_key(|path| path.exit);
+        results
+    }
+}
+
+/// Collect cells of the route ending with `node`
+fn route_cells(nodes: &[Node], node: usize) -> Vec<usize> {
+    let mut cells = Vec::new();
+    let mut current = Some(node);
+    while let Some(node) = current {
+        cells.push(nodes[node].idx);
+        current = nodes[node].previous;
+    }
+    cells.reverse();
+    cells
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::effects::{MarkEffect, Turn};
+    use crate::field::Mark;
+    use crate::movement::DungeonModifiers;
+    use wasm_bindgen_test::*;
+
+    #[wasm_bindgen_test]
+    fn test_top_paths() {
+        // loop around the center: exit is either reached at once, or on the way around
+        let mut subway = Subway::loop_field();
+        subway.init(&DungeonModifiers::default());
+
+        let paths = subway.top_paths(3);
+        let to_exit: Vec<&RankedPath> = paths.iter().filter(|path| path.exit == 127).collect();
+        assert_eq!(to_exit.len(), 3);
+        assert_eq!(to_exit[0].get_cells(), vec![128, 127]);
+        assert!((to_exit[0].probability - 1. / 3.).abs() < 1e-12);
+        assert_eq!(to_exit[1].get_cells(), vec![128, 148, 147, 127]);
+        assert!((to_exit[1].probability - 0.8 * 0.15 / 3.).abs() < 1e-12);
+        assert_eq!(to_exit[2].get_cells(), vec![128, 148, 147, 146, 126, 127]);
+        assert!((to_exit[2].probability - 0.8 * 0.85 * 0.8 * 0.15 / 3.).abs() < 1e-12);
+
+        // routes of each exit never add up to more than its absorption
+        let absorbed = subway.solve_absorption();
+        for exit in subway.get_exit_cells() {
+            let total: f64 = paths
+                .iter()
+                .filter(|path| path.exit == exit)
+                .map(|path| path.probability)
+                .sum();
+            assert!(total <= absorbed[exit] + 1e-12);
+        }
+
+        // effects of marks are not followed
+        subway.set_mark(147, Mark::Trap);
+        subway.set_mark_effect(Mark::Trap, MarkEffect::force_turn(Turn::Back));
+        assert!(subway.top_paths(3).is_empty());
+    }
+}
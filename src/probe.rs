@@ -0,0 +1,282 @@
+use std::collections::VecDeque;
+
+use wasm_bindgen::prelude::*;
+
+use crate::field::{Cell, MoverField, Subway, PROPAGATION_EPSILON, PROPAGATION_MAX_STEPS};
+
+/// Movers that have not visited tracked cells yet
+///
+/// Every tracked cell (target) has its own copy of the movers, which loses
+/// the mass entering the target. Copies of a dungeon floor track cells
+/// of all floors, so groups climbing ladders are followed too.
+#[derive(Clone)]
+pub(crate) struct FirstVisits {
+    /// cell of every target, if it is on this floor
+    pub(crate) cells: Vec<Option<usize>>,
+    /// movers that have not visited the target yet, by target
+    pub(crate) pending: Vec<MoverField>,
+    /// pending movers waiting in delaying cells, by target
+    pub(crate) delayed: Vec<VecDeque<MoverField>>,
+    /// mass that has visited the target, by target
+    pub(crate) visits: Vec<f64>,
+}
+
+#[wasm_bindgen]
+impl Subway {
+    /// Calculate distribution of the number of visits to `cells` before leaving the field
+    ///
+    /// Element `k` of the result is the probability of exactly `k` visits in total,
+    /// the last one (`k = max_count`) collects `max_count` visits and more.
+    /// The start at the entrance counts as a visit too, and groups stopped
+    /// by mark effects are counted with the visits they made.
+    pub fn encounter_distribution(&self, cells: &[usize], max_count: usize) -> Vec<f64> {
+        let mut probes: Vec<usize> = cells
+            .iter()
+            .copied()
+            .filter(|&idx| idx < self.field.len())
+            .collect();
+        probes.sort_unstable();
+        probes.dedup();
+
+        let scratch = self.scratch();
+
+        // movers split by the number of visits so far, and their delayed groups
+        let mut layers = vec![MoverField::zeros(4, self.field.len()); max_count + 1];
+        let mut delayed = vec![VecDeque::new(); max_count + 1];
+        let delayed_mass = |delayed: &VecDeque<MoverField>| -> f64 {
+            delayed.iter().map(|movers| movers.sum()).sum()
+        };
+        let start_visits = probes
+            .iter()
+            .filter(|&&idx| self.field[idx] == Cell::Entrance)
+            .count()
+            .min(max_count);
+        layers[start_visits] = scratch.movers.clone();
+
+        let mut result = vec![0.; max_count + 1];
+        let mut step_number = 1;
+        while step_number <= PROPAGATION_MAX_STEPS
+            && layers.iter().map(|movers| movers.sum()).sum::<f64>()
+                + delayed.iter().map(delayed_mass).sum::<f64>()
+                > PROPAGATION_EPSILON
+        {
+            for _ in 0..self.modifiers.moves_per_step() {
+                // visitors of probed cells go one layer up
+                for count in (0..max_count).rev() {
+                    for &idx in &probes {
+                        let visitors = layers[count].column(idx).into_owned();
+                        layers[count].column_mut(idx).fill(0.);
+                        let mut next_layer = layers[count + 1].column_mut(idx);
+                        next_layer += visitors;
+                    }
+                }
+                for (count, movers) in layers.iter_mut().enumerate() {
+                    for (idx, _) in &scratch.arrivals {
+                        if scratch.is_exit(*idx, step_number) {
+                            result[count] += movers.column(*idx).sum();
+                        }
+                    }
+                    result[count] +=
+                        scratch.apply_effects_to(movers, &mut delayed[count], step_number);
+                    *movers = scratch.advance(movers, step_number);
+                }
+            }
+            step_number += 1;
+        }
+
+        // groups that never leave are counted where they are
+        for (count, movers) in layers.iter().enumerate() {
+            result[count] += movers.sum() + delayed_mass(&delayed[count]);
+        }
+        result
+    }
+
+    /// Calculate probability of visiting at least one of `cells` before leaving the field
+    pub fn encounter_probability(&self, cells: &[usize]) -> f64 {
+        let distribution = self.encounter_distribution(cells, 1);
+        distribution[1]
+    }
+
+    /// Turn tracking of first visits of cells on or off, starting with the next `init`
+    ///
+    /// Tracking keeps a copy of the movers for every passable cell and moves
+    /// all of them on every move, so stepping of large fields gets much slower.
+    pub fn set_first_visit_tracking(&mut self, enabled: bool) {
+        self.first_visit_tracking = enabled;
+    }
+
+    /// Get probabilities of visiting each cell at least once, up to the last performed step
+    ///
+    /// Unlike accumulated visits (`get_visited_probabilities`), revisits
+    /// are not counted, so the values never exceed 100%. The result is empty
+    /// unless tracking was turned on before `init` (see `set_first_visit_tracking`),
+    /// and after `restore`, as snapshots don't keep tracked movers.
+    pub fn get_first_visit_probabilities(&self) -> Vec<f64> {
+        let first_visits = match &self.first_visits {
+            Some(first_visits) => first_visits,
+            None => return Vec::new(),
+        };
+        let mut result = vec![0.; self.field.len()];
+        for (cell, visits) in first_visits.cells.iter().zip(&first_visits.visits) {
+            if let Some(idx) = cell {
+                result[*idx] = *visits;
+            }
+        }
+        result
+    }
+}
+
+impl Subway {
+    /// Cells that can be visited, tracked for first visits
+    pub(crate) fn first_visit_cells(&self) -> impl Iterator<Item = usize> + '_ {
+        (0..self.field.len()).filter(|&idx| self.is_inner(idx) && self.field[idx] != Cell::Wall)
+    }
+
+    /// Start tracking first visits of targets, given by their cells on this floor
+    ///
+    /// The group has visited the entrance at the start.
+    pub(crate) fn start_first_visits(&mut self, cells: Vec<Option<usize>>) {
+        let mut first_visits = FirstVisits {
+            pending: vec![self.movers.clone(); cells.len()],
+            delayed: vec![self.delayed.clone(); cells.len()],
+            visits: vec![0.; cells.len()],
+            cells,
+        };
+        for (target, cell) in first_visits.cells.iter().enumerate() {
+            if let Some(idx) = *cell {
+                if self.field[idx] == Cell::Entrance {
+                    first_visits.visits[target] = self.visited[idx];
+                    first_visits.pending[target].fill(0.);
+                }
+            }
+        }
+        self.first_visits = Some(first_visits);
+    }
+
+    /// Count first visits of tracked cells and apply mark effects, as in `arrive`
+    pub(crate) fn arrive_first_visits(&mut self, step_number: u32) {
+        let mut first_visits = match self.first_visits.take() {
+            Some(first_visits) => first_visits,
+            None => return,
+        };
+        for target in 0..first_visits.cells.len() {
+            let pending = &mut first_visits.pending[target];
+            if let Some(idx) = first_visits.cells[target] {
+                first_visits.visits[target] += pending.column(idx).sum();
+                pending.column_mut(idx).fill(0.);
+            }
+            self.apply_effects_to(pending, &mut first_visits.delayed[target], step_number);
+        }
+        self.first_visits = Some(first_visits);
+    }
+
+    /// Move movers that have not visited tracked cells yet, as in `depart`
+    pub(crate) fn depart_first_visits(&mut self, step_number: u32) {
+        let mut first_visits = match self.first_visits.take() {
+            Some(first_visits) => first_visits,
+            None => return,
+        };
+        for pending in first_visits.pending.iter_mut() {
+            if pending.iter().any(|&v| v != 0.) {
+                *pending = self.advance(pending, step_number);
+            }
+        }
+        self.first_visits = Some(first_visits);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::effects::MarkEffect;
+    use crate::field::Mark;
+    use crate::movement::DungeonModifiers;
+    use wasm_bindgen_test::*;
+
+    fn assert_close(a: f64, b: f64) {
+        assert!((a - b).abs() < 1e-9, "{} != {}", a, b);
+    }
+
+    #[wasm_bindgen_test]
+    fn test_encounters() {
+        // loop around the center: exit is either reached at once, or after
+        // going around through 86
+        let mut subway = Subway::new();
+        subway.set_field(86, Cell::Pass);
+        subway.set_field(87, Cell::Pass);
+        subway.set_field(88, Cell::Pass);
+        subway.set_field(106, Cell::Pass);
+        subway.set_field(108, Cell::Pass);
+        subway.set_field(126, Cell::Pass);
+        subway.set_field(127, Cell::Exit);
+        subway.set_field(128, Cell::Entrance);
+        subway.init(&DungeonModifiers::default());
+
+        assert_close(subway.encounter_probability(&[86]), 0.5);
+        assert_close(subway.encounter_probability(&[86, 87, 88]), 0.5);
+        assert_close(subway.encounter_probability(&[127]), 1.);
+
+        let distribution = subway.encounter_distribution(&[86, 87, 88], 5);
+        assert_eq!(distribution.len(), 6);
+        assert_close(distribution[0], 0.5);
+        assert_close(distribution[3], 0.5);
+
+        // overflow goes into the last element
+        let distribution = subway.encounter_distribution(&[86, 87, 88], 2);
+        assert_close(distribution[2], 0.5);
+
+        // entrance is visited at start
+        assert_close(subway.encounter_probability(&[128]), 1.);
+
+        // half of groups going around are stopped at 108, delays change nothing
+        subway.set_mark(108, Mark::FinalBoss);
+        subway.set_mark_effect(Mark::FinalBoss, MarkEffect::absorb(0.5));
+        subway.set_mark(87, Mark::Luck);
+        subway.set_mark_effect(Mark::Luck, MarkEffect::delay(2));
+        assert_close(subway.encounter_probability(&[108]), 0.5);
+        assert_close(subway.encounter_probability(&[86]), 0.25);
+        let distribution = subway.encounter_distribution(&[86, 87, 88], 5);
+        assert_close(distribution[0], 0.75);
+        assert_close(distribution[3], 0.25);
+    }
+
+    #[wasm_bindgen_test]
+    fn test_first_visits() {
+        // dead end: group bounces between the entrance and the end of corridor
+        let mut subway = Subway::new();
+        subway.set_field(128, Cell::Entrance);
+        subway.set_field(127, Cell::Pass);
+        subway.set_field(126, Cell::Pass);
+        subway.init(&DungeonModifiers::default());
+        assert!(subway.get_first_visit_probabilities().is_empty());
+        subway.set_first_visit_tracking(true);
+        subway.init(&DungeonModifiers::default());
+        assert_eq!(subway.get_first_visit_probabilities()[128], 1.);
+        assert_eq!(subway.get_first_visit_probabilities()[127], 0.);
+
+        for step_number in 1..=10 {
+            subway.step(step_number);
+        }
+        let visited = subway.get_visited_probabilities();
+        let first_visits = subway.get_first_visit_probabilities();
+        assert_eq!(visited[127], 5.);
+        assert_eq!(first_visits[127], 1.);
+        assert_eq!(visited[126], 3.);
+        assert_eq!(first_visits[126], 1.);
+        assert_eq!(first_visits[125], 0.);
+
+        // groups stopped by a boss on the way never get to the end
+        subway.set_mark(127, Mark::FinalBoss);
+        subway.set_mark_effect(Mark::FinalBoss, MarkEffect::absorb(0.4));
+        subway.init(&DungeonModifiers::default());
+        subway.run_until(1e-12, 100);
+        let first_visits = subway.get_first_visit_probabilities();
+        assert_close(first_visits[127], 1.);
+        assert_close(first_visits[126], 0.6);
+
+        // analyses step copies of the field without tracking
+        let scratch = subway.scratch();
+        assert!(scratch.get_first_visit_probabilities().is_empty());
+        assert!(!subway.get_first_visit_probabilities().is_empty());
+    }
+}
@@ -0,0 +1,458 @@
+use wasm_bindgen::prelude::*;
+
+use crate::effects::Effect;
+use crate::field::{Cell, Direction, Subway, DIRECTIONS, PROPAGATION_MAX_STEPS};
+
+/// Quantile of the normal distribution for 95% confidence intervals
+const CONFIDENCE_Z: f64 = 1.96;
+
+/// Small seedable random number generator (SplitMix64)
+pub(crate) struct SplitMix64 {
+    state: u64,
+}
+
+impl SplitMix64 {
+    pub(crate) fn new(seed: u64) -> Self {
+        SplitMix64 { state: seed }
+    }
+
+    pub(crate) fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    /// Uniform number in [0, 1)
+    pub(crate) fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+/// How a walk of a single group ended
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub(crate) enum WalkEnd {
+    /// left the field through the cell
+    Exit(usize),
+    /// had nowhere to go
+    Lost,
+    /// was stopped by a mark effect
+    Stopped,
+    /// still inside the field after the step limit
+    Stuck,
+}
+
+/// Empirical frequency of leaving the field through a single exit
+#[wasm_bindgen]
+#[derive(Clone, Copy)]
+pub struct ExitFrequency {
+    /// exit cell index
+    pub idx: usize,
+    /// number of groups leaving through this exit
+    pub count: u32,
+    /// share of groups leaving through this exit
+    pub frequency: f64,
+    /// lower bound of the 95% confidence interval
+    pub lower: f64,
+    /// upper bound of the 95% confidence interval
+    pub upper: f64,
+}
+
+impl ExitFrequency {
+    /// Calculate frequency with Wilson score interval
+    fn new(idx: usize, count: u32, runs: u32) -> Self {
+        let mut result = ExitFrequency {
+            idx,
+            count,
+            frequency: 0.,
+            lower: 0.,
+            upper: 1.,
+        };
+        if runs == 0 {
+            return result;
+        }
+        let n = runs as f64;
+        let p = count as f64 / n;
+        let z2 = CONFIDENCE_Z * CONFIDENCE_Z;
+        let center = (p + z2 / (2. * n)) / (1. + z2 / n);
+        let half_width =
+            CONFIDENCE_Z / (1. + z2 / n) * (p * (1. - p) / n + z2 / (4. * n * n)).sqrt();
+        result.frequency = p;
+        result.lower = (center - half_width).max(0.);
+        result.upper = (center + half_width).min(1.);
+        result
+    }
+}
+
+/// Outcome of simulating individual groups
+#[wasm_bindgen]
+#[derive(Clone)]
+pub struct SimulationSummary {
+    /// number of simulated groups
+    pub runs: u32,
+    /// groups that had nowhere to go without leaving the field
+    pub lost: u32,
+    /// groups stopped by mark effects
+    pub stopped: u32,
+    /// groups still inside the field after the step limit
+    pub stuck: u32,
+    exits: Vec<ExitFrequency>,
+}
+
+#[wasm_bindgen]
+impl SimulationSummary {
+    /// Get frequencies of leaving through every exit cell (and the entrance)
+    pub fn get_exits(&self) -> Vec<ExitFrequency> {
+        self.exits.clone()
+    }
+
+    /// Get frequency of leaving through cell `idx`
+    pub fn get_frequency(&self, idx: usize) -> f64 {
+        self.exits
+            .iter()
+            .find(|exit| exit.idx == idx)
+            .map_or(0., |exit| exit.frequency)
+    }
+}
+
+/// Route of a single simulated group
+#[wasm_bindgen]
+#[derive(Clone)]
+pub struct SampledPath {
+    cells: Vec<usize>,
+    headings: Vec<u8>,
+    jumps: Vec<usize>,
+    end: WalkEnd,
+}
+
+#[wasm_bindgen]
+impl SampledPath {
+    /// Get cells entered by the group, starting with the entrance
+    pub fn get_cells(&self) -> Vec<usize> {
+        self.cells.clone()
+    }
+
+    /// Get heading (0 to 3 for north, east, south and west) on entering each cell
+    ///
+    /// The entrance, where the group starts, is reported with north heading.
+    pub fn get_headings(&self) -> Vec<u8> {
+        self.headings.clone()
+    }
+
+    /// Get positions in the path of cells reached by jumping
+    pub fn get_jumps(&self) -> Vec<usize> {
+        self.jumps.clone()
+    }
+
+    /// Get the cell the group left the field through
+    ///
+    /// Nothing if the group got lost, was stopped or did not leave in time.
+    pub fn get_exit(&self) -> Option<usize> {
+        match self.end {
+            WalkEnd::Exit(idx) => Some(idx),
+            _ => None,
+        }
+    }
+
+    /// Tell if the group got into a cell with nowhere to go
+    pub fn is_lost(&self) -> bool {
+        self.end == WalkEnd::Lost
+    }
+
+    /// Tell if the group was stopped by a mark effect
+    pub fn is_stopped(&self) -> bool {
+        self.end == WalkEnd::Stopped
+    }
+}
+
+impl Subway {
+    /// Choose a random move of a mover in cell `idx` moving in direction `heading`
+    ///
+    /// Returns (relative direction, next cell index, next heading),
+    /// or nothing if there is nowhere to go.
+    pub(crate) fn sample_move(
+        &self,
+        idx: usize,
+        heading: Direction,
+        move_count: u32,
+        rng: &mut SplitMix64,
+    ) -> Option<(usize, usize, usize)> {
+        let x = rng.next_f64();
+        let mut cumulative = 0.;
+        let mut chosen = None;
+        for (dir, next_idx, next_heading, prob) in self.mover_moves(idx, heading, move_count) {
+            cumulative += prob;
+            chosen = Some((dir, next_idx, next_heading));
+            if x < cumulative {
+                return chosen;
+            }
+        }
+        // forgive rounding errors of probabilities summing up to 1
+        if cumulative > 1. - 1e-9 {
+            chosen
+        } else {
+            None
+        }
+    }
+
+    /// Walk a single group from the entrance until it leaves the field, or `max_steps` are done
+    ///
+    /// `on_move` is called with (cell index, heading, relative direction) of each
+    /// cell the group enters, starting with the entrance. Mark effects act
+    /// on the group as in `step`.
+    pub(crate) fn walk(
+        &self,
+        rng: &mut SplitMix64,
+        max_steps: u32,
+        mut on_move: impl FnMut(usize, usize, usize),
+    ) -> WalkEnd {
+        let entrance = match self.field.iter().position(|&cell| cell == Cell::Entrance) {
+            Some(entrance) => entrance,
+            None => return WalkEnd::Lost,
+        };
+        // same as in `init`: the group leaves the entrance facing away from it
+        on_move(entrance, Direction::North as usize, 0);
+        let (mut idx, mut heading) = match self.sample_move(entrance, Direction::North, 0, rng) {
+            Some((dir, next_idx, next_heading)) => {
+                on_move(next_idx, next_heading, dir);
+                (next_idx, next_heading)
+            }
+            None => return WalkEnd::Lost,
+        };
+
+        // moves left to wait in a delaying cell
+        let mut waiting = 0;
+        for step_number in 1..=max_steps {
+            for _ in 0..self.modifiers.moves_per_step() {
+                if waiting > 1 {
+                    waiting -= 1;
+                    continue;
+                } else if waiting == 1 {
+                    // released groups leave without being affected again
+                    waiting = 0;
+                } else {
+                    if self.is_exit(idx, step_number) {
+                        return WalkEnd::Exit(idx);
+                    }
+                    match self.cell_effect(idx) {
+                        Effect::None => (),
+                        Effect::Absorb(probability) => {
+                            if rng.next_f64() < probability {
+                                return WalkEnd::Stopped;
+                            }
+                        }
+                        Effect::Delay(steps) => {
+                            waiting = steps * self.modifiers.moves_per_step();
+                            if waiting > 0 {
+                                continue;
+                            }
+                        }
+                        Effect::ForceTurn(turn) => heading = (heading + turn as usize) % 4,
+                    }
+                }
+                match self.sample_move(idx, DIRECTIONS[heading], step_number, rng) {
+                    Some((dir, next_idx, next_heading)) => {
+                        on_move(next_idx, next_heading, dir);
+                        (idx, heading) = (next_idx, next_heading);
+                    }
+                    None => return WalkEnd::Lost,
+                }
+            }
+        }
+        WalkEnd::Stuck
+    }
+}
+
+#[wasm_bindgen]
+impl Subway {
+    /// Simulate `runs` individual groups walking through the field
+    ///
+    /// This is an independent check of the probabilities calculated by `step`:
+    /// exit frequencies converge to `solve_absorption` results as `runs` grows.
+    /// Groups are walked for at most `PROPAGATION_MAX_STEPS` steps.
+    pub fn simulate_runs(&self, runs: u32, seed: u64) -> SimulationSummary {
+        let exit_cells: Vec<usize> = (0..self.field.len())
+            .filter(|&idx| {
+                self.is_inner(idx) && matches!(self.field[idx], Cell::Exit | Cell::Entrance)
+            })
+            .collect();
+        let mut counts = vec![0; exit_cells.len()];
+        let mut summary = SimulationSummary {
+            runs,
+            lost: 0,
+            stopped: 0,
+            stuck: 0,
+            exits: Vec::new(),
+        };
+
+        let mut rng = SplitMix64::new(seed);
+        for _ in 0..runs {
+            match self.walk(&mut rng, PROPAGATION_MAX_STEPS, |_, _, _| {}) {
+                WalkEnd::Exit(idx) => {
+                    if let Some(pos) = exit_cells.iter().position(|&exit| exit == idx) {
+                        counts[pos] += 1;
+                    }
+                }
+                WalkEnd::Lost => summary.lost += 1,
+                WalkEnd::Stopped => summary.stopped += 1,
+                WalkEnd::Stuck => summary.stuck += 1,
+            }
+        }
+        summary.exits = exit_cells
+            .iter()
+            .zip(counts)
+            .map(|(&idx, count)| ExitFrequency::new(idx, count, runs))
+            .collect();
+        summary
+    }
+
+    /// Simulate a single group walking for at most `max_steps` steps
+    pub fn sample_path(&self, seed: u64, max_steps: u32) -> SampledPath {
+        let mut path = SampledPath {
+            cells: Vec::new(),
+            headings: Vec::new(),
+            jumps: Vec::new(),
+            end: WalkEnd::Stuck,
+        };
+        let mut rng = SplitMix64::new(seed);
+        path.end = self.walk(&mut rng, max_steps, |idx, heading, dir| {
+            if dir >= 4 {
+                path.jumps.push(path.cells.len());
+            }
+            path.cells.push(idx);
+            path.headings.push(heading as u8);
+        });
+        path
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::effects::{MarkEffect, Turn};
+    use crate::field::Mark;
+    use crate::movement::DungeonModifiers;
+    use wasm_bindgen_test::*;
+
+    #[wasm_bindgen_test]
+    fn test_simulation_matches_absorption() {
+        let mut subway = Subway::loop_field();
+        subway.init(&DungeonModifiers {
+            jumpy: true,
+            ..Default::default()
+        });
+
+        let summary = subway.simulate_runs(20000, 42);
+        assert_eq!(summary.runs, 20000);
+        assert_eq!(summary.lost + summary.stuck, 0);
+        let absorbed = subway.solve_absorption();
+        for exit in summary.get_exits() {
+            assert!(exit.lower <= exit.frequency && exit.frequency <= exit.upper);
+            assert!(
+                exit.lower <= absorbed[exit.idx] && absorbed[exit.idx] <= exit.upper,
+                "{} is out of [{}, {}]",
+                absorbed[exit.idx],
+                exit.lower,
+                exit.upper
+            );
+        }
+
+        // seeded runs repeat
+        let again = subway.simulate_runs(20000, 42);
+        assert_eq!(again.get_frequency(127), summary.get_frequency(127));
+    }
+
+    #[wasm_bindgen_test]
+    fn test_simulation_effects() {
+        // a boss on the way around, and the way back is longer
+        let mut subway = Subway::loop_field();
+        subway.set_mark(88, Mark::FinalBoss);
+        subway.set_mark_effect(Mark::FinalBoss, MarkEffect::absorb(0.3));
+        subway.set_mark(106, Mark::Trap);
+        subway.set_mark_effect(Mark::Trap, MarkEffect::force_turn(Turn::Back));
+        subway.set_mark(147, Mark::Luck);
+        subway.set_mark_effect(Mark::Luck, MarkEffect::delay(2));
+        subway.init(&DungeonModifiers::default());
+        subway.run_until(1e-12, 1000);
+
+        let runs = 20000;
+        let summary = subway.simulate_runs(runs, 3);
+        let absorbed = subway.solve_absorption();
+        for exit in summary.get_exits() {
+            assert!(exit.lower <= absorbed[exit.idx] && absorbed[exit.idx] <= exit.upper);
+        }
+        let stopped = ExitFrequency::new(0, summary.stopped, runs);
+        let mass = subway.get_stopped_mass();
+        assert!(stopped.lower <= mass && mass <= stopped.upper);
+    }
+
+    #[wasm_bindgen_test]
+    fn test_sample_path() {
+        // corridor with a closed cell at the end, reachable by jumping only
+        let mut subway = Subway::new();
+        subway.set_field(122, Cell::Pass);
+        for idx in 124..=129 {
+            subway.set_field(idx, Cell::Pass);
+        }
+        subway.set_field(130, Cell::Entrance);
+        subway.set_field(150, Cell::Exit);
+        subway.init(&DungeonModifiers {
+            jumpy: true,
+            ..Default::default()
+        });
+
+        let mut ends = (0, 0);
+        for seed in 0..50 {
+            let path = subway.sample_path(seed, 100);
+            let cells = path.get_cells();
+            assert_eq!(cells[0], 130);
+            assert_eq!(cells.len(), path.get_headings().len());
+            match path.get_exit() {
+                Some(exit) => {
+                    assert_eq!(exit, *cells.last().unwrap());
+                    ends.0 += 1;
+                }
+                None => {
+                    // the only way to get lost is to jump into the closed cell
+                    assert!(path.is_lost());
+                    assert_eq!(*cells.last().unwrap(), 122);
+                    assert_eq!(path.get_jumps(), vec![cells.len() - 1]);
+                    assert_eq!(path.get_headings().last(), Some(&3));
+                    ends.1 += 1;
+                }
+            }
+            // neighbouring cells, or two apart for jumps
+            for pos in 1..cells.len() {
+                let distance = cells[pos].abs_diff(cells[pos - 1]);
+                if path.get_jumps().contains(&pos) {
+                    assert!(distance == 2 || distance == 40);
+                } else {
+                    assert!(distance == 1 || distance == 20);
+                }
+            }
+        }
+        assert!(ends.0 > 0 && ends.1 > 0);
+    }
+
+    #[wasm_bindgen_test]
+    fn test_simulation_lost() {
+        // closed cell at the end of a corridor, reachable by jumping only
+        let mut subway = Subway::new();
+        subway.set_field(122, Cell::Pass);
+        for idx in 124..=129 {
+            subway.set_field(idx, Cell::Pass);
+        }
+        subway.set_field(130, Cell::Entrance);
+        subway.init(&DungeonModifiers {
+            jumpy: true,
+            ..Default::default()
+        });
+        subway.run_until(1e-12, 1000);
+
+        let runs = 20000;
+        let summary = subway.simulate_runs(runs, 7);
+        let lost = ExitFrequency::new(0, summary.lost, runs);
+        assert!(lost.lower <= subway.get_lost_mass() && subway.get_lost_mass() <= lost.upper);
+    }
+}
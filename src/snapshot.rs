@@ -0,0 +1,247 @@
+use wasm_bindgen::prelude::*;
+
+use std::collections::VecDeque;
+
+use crate::field::{FlowField, MoverField, Subway, VisitedField};
+use crate::ledger::LedgerEntry;
+use crate::movement::DungeonModifiers;
+
+/// Saved simulation state of a `Subway`
+///
+/// Field layout is not a part of the state: a snapshot is meant
+/// to be restored onto the same field it was taken from.
+#[wasm_bindgen]
+#[derive(Clone)]
+pub struct SubwaySnapshot {
+    visited: VisitedField,
+    movers: MoverField,
+    last_step: u32,
+    modifiers: DungeonModifiers,
+    arrivals: Vec<(usize, Vec<f64>)>,
+    early_entrance_visits: f64,
+    ledger: Vec<LedgerEntry>,
+    flows: FlowField,
+    delayed: VecDeque<MoverField>,
+}
+
+/// Encode modifiers as bit flags
+fn modifiers_to_bits(modifiers: &DungeonModifiers) -> u32 {
+    modifiers.jumpy as u32 | (modifiers.double_speed as u32) << 1 | (modifiers.reversed as u32) << 2
+}
+
+/// Decode modifiers from bit flags
+fn modifiers_from_bits(bits: u32) -> DungeonModifiers {
+    DungeonModifiers {
+        jumpy: bits & 1 != 0,
+        double_speed: bits & 2 != 0,
+        reversed: bits & 4 != 0,
+    }
+}
+
+#[wasm_bindgen]
+impl SubwaySnapshot {
+    /// Get number of the last step performed before the snapshot
+    pub fn get_last_step(&self) -> u32 {
+        self.last_step
+    }
+
+    /// Serialize into a flat array of numbers
+    ///
+    /// Layout: last step, modifier flags, early entrance visits, number of cells, visited field,
+    /// movers (4 per cell), number of exits, and then for every exit its index,
+    /// number of steps and arrivals on each step, followed by number of ledger entries
+    /// and 8 numbers per entry (step, start, in flight, exits, entrance, lost, stopped,
+    /// transferred),
+    /// edge flows (8 per cell), and finally number of moves groups are delayed for
+    /// and waiting movers for each of them (4 per cell).
+    pub fn to_vec(&self) -> Vec<f64> {
+        let mut data = vec![
+            self.last_step as f64,
+            modifiers_to_bits(&self.modifiers) as f64,
+            self.early_entrance_visits,
+            self.visited.len() as f64,
+        ];
+        data.extend_from_slice(self.visited.as_slice());
+        data.extend_from_slice(self.movers.as_slice());
+        data.push(self.arrivals.len() as f64);
+        for (idx, series) in &self.arrivals {
+            data.push(*idx as f64);
+            data.push(series.len() as f64);
+            data.extend_from_slice(series);
+        }
+        data.push(self.ledger.len() as f64);
+        for entry in &self.ledger {
+            data.extend_from_slice(&[
+                entry.step as f64,
+                entry.start,
+                entry.in_flight,
+                entry.exits,
+                entry.entrance,
+                entry.lost,
+                entry.stopped,
+                entry.transferred,
+            ]);
+        }
+        data.extend_from_slice(self.flows.as_slice());
+        data.push(self.delayed.len() as f64);
+        for movers in &self.delayed {
+            data.extend_from_slice(movers.as_slice());
+        }
+        data
+    }
+
+    /// Deserialize from a flat array made by `to_vec`
+    ///
+    /// Returns nothing if the data is malformed.
+    pub fn from_vec(data: &[f64]) -> Option<SubwaySnapshot> {
+        const HEADER: usize = 4;
+        let flat_size = *data.get(HEADER - 1)? as usize;
+        let mut pos = flat_size.checked_mul(5)?.checked_add(HEADER)?;
+        if data.len() <= pos {
+            return None;
+        }
+        let mut snapshot = SubwaySnapshot {
+            visited: VisitedField::from_column_slice(&data[HEADER..HEADER + flat_size]),
+            movers: MoverField::from_column_slice(4, flat_size, &data[HEADER + flat_size..pos]),
+            last_step: data[0] as u32,
+            modifiers: modifiers_from_bits(data[1] as u32),
+            arrivals: Vec::new(),
+            early_entrance_visits: data[2],
+            ledger: Vec::new(),
+            flows: FlowField::zeros(8, flat_size),
+            delayed: VecDeque::new(),
+        };
+
+        let num_exits = data[pos] as usize;
+        pos += 1;
+        for _ in 0..num_exits {
+            let header = data.get(pos..pos + 2)?;
+            let (idx, len) = (header[0] as usize, header[1] as usize);
+            let end = pos.checked_add(2)?.checked_add(len)?;
+            let series = data.get(pos + 2..end)?;
+            if idx >= flat_size {
+                return None;
+            }
+            snapshot.arrivals.push((idx, series.to_vec()));
+            pos = end;
+        }
+
+        let num_entries = *data.get(pos)? as usize;
+        pos += 1;
+        for _ in 0..num_entries {
+            let values = data.get(pos..pos + 8)?;
+            snapshot.ledger.push(LedgerEntry {
+                step: values[0] as u32,
+                start: values[1],
+                in_flight: values[2],
+                exits: values[3],
+                entrance: values[4],
+                lost: values[5],
+                stopped: values[6],
+                transferred: values[7],
+            });
+            pos += 8;
+        }
+        let end = pos.checked_add(flat_size.checked_mul(8)?)?;
+        snapshot.flows.copy_from_slice(data.get(pos..end)?);
+        pos = end;
+        let num_delayed = *data.get(pos)? as usize;
+        pos += 1;
+        for _ in 0..num_delayed {
+            let end = pos.checked_add(flat_size.checked_mul(4)?)?;
+            let movers = data.get(pos..end)?;
+            snapshot
+                .delayed
+                .push_back(MoverField::from_column_slice(4, flat_size, movers));
+            pos = end;
+        }
+        if pos != data.len() {
+            return None;
+        }
+        Some(snapshot)
+    }
+}
+
+#[wasm_bindgen]
+impl Subway {
+    /// Save simulation state
+    pub fn snapshot(&self) -> SubwaySnapshot {
+        SubwaySnapshot {
+            visited: self.visited.clone(),
+            movers: self.movers.clone(),
+            last_step: self.last_step,
+            modifiers: self.modifiers,
+            arrivals: self.arrivals.clone(),
+            early_entrance_visits: self.early_entrance_visits,
+            ledger: self.ledger.clone(),
+            flows: self.flows.clone(),
+            delayed: self.delayed.clone(),
+        }
+    }
+
+    /// Restore simulation state saved with `snapshot`
+    ///
+    /// Stepping can be continued from `SubwaySnapshot::get_last_step() + 1`.
+    /// Snapshots of fields with another number of cells are not restored.
+    pub fn restore(&mut self, snapshot: &SubwaySnapshot) -> bool {
+        if snapshot.visited.len() != self.field.len() {
+            return false;
+        }
+        self.visited = snapshot.visited.clone();
+        self.movers = snapshot.movers.clone();
+        self.last_step = snapshot.last_step;
+        if self.modifiers != snapshot.modifiers {
+            self.modifiers = snapshot.modifiers;
+            self.compile_transitions();
+        }
+        self.arrivals = snapshot.arrivals.clone();
+        self.early_entrance_visits = snapshot.early_entrance_visits;
+        self.ledger = snapshot.ledger.clone();
+        self.flows = snapshot.flows.clone();
+        self.delayed = snapshot.delayed.clone();
+        self.first_visits = None;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use wasm_bindgen_test::*;
+
+    #[wasm_bindgen_test]
+    fn test_snapshot_resume() {
+        let mut subway = Subway::loop_field();
+        subway.init(&DungeonModifiers {
+            jumpy: true,
+            ..Default::default()
+        });
+        for step_number in 1..=10 {
+            subway.step(step_number);
+        }
+        let snapshot = subway.snapshot();
+        for step_number in 11..=30 {
+            subway.step(step_number);
+        }
+        let expected = subway.snapshot().to_vec();
+
+        // scrub back and forth
+        subway.init(&DungeonModifiers::default());
+        subway.restore(&snapshot);
+        assert_eq!(subway.get_last_step(), 10);
+        for step_number in 11..=30 {
+            subway.step(step_number);
+        }
+        assert_eq!(subway.snapshot().to_vec(), expected);
+
+        // serialized
+        let restored = SubwaySnapshot::from_vec(&snapshot.to_vec()).unwrap();
+        assert_eq!(restored.to_vec(), snapshot.to_vec());
+        assert!(restored.modifiers == snapshot.modifiers);
+        assert!(SubwaySnapshot::from_vec(&expected[..expected.len() - 1]).is_none());
+        // length of the first arrivals series, too large to add up
+        let mut corrupted = snapshot.to_vec();
+        corrupted[4 + 5 * subway.get_flat_size() + 2] = 1e300;
+        assert!(SubwaySnapshot::from_vec(&corrupted).is_none());
+    }
+}
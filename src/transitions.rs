@@ -0,0 +1,430 @@
+use std::rc::Rc;
+
+use wasm_bindgen::prelude::*;
+
+use crate::field::{Cell, Subway, DIRECTIONS};
+use crate::ledger::LedgerEntry;
+
+/// Index of a mover state (cell `idx`, heading `heading`)
+///
+/// Matches the layout of `MoverField` storage, so movers can be used
+/// as a state vector directly.
+fn state_idx(idx: usize, heading: usize) -> usize {
+    idx * 4 + heading
+}
+
+/// Sparse matrix of transitions between mover states, stored by rows
+#[derive(Clone)]
+pub(crate) struct TransitionMatrix {
+    /// start of entries of each row, and the end of the last row
+    row_starts: Vec<usize>,
+    /// column of each entry
+    columns: Vec<usize>,
+    /// value of each entry
+    values: Vec<f64>,
+}
+
+impl TransitionMatrix {
+    /// Number of rows (and columns)
+    fn size(&self) -> usize {
+        self.row_starts.len() - 1
+    }
+
+    /// Entries of `row` as (column, value) pairs
+    fn row(&self, row: usize) -> impl Iterator<Item = (usize, f64)> + '_ {
+        let range = self.row_starts[row]..self.row_starts[row + 1];
+        self.columns[range.clone()]
+            .iter()
+            .copied()
+            .zip(self.values[range].iter().copied())
+    }
+
+    /// Build a matrix from rows of (column, value) pairs
+    fn from_rows(rows: impl Iterator<Item = Vec<(usize, f64)>>) -> Self {
+        let mut matrix = TransitionMatrix {
+            row_starts: vec![0],
+            columns: Vec::new(),
+            values: Vec::new(),
+        };
+        for row in rows {
+            for (column, value) in row {
+                matrix.columns.push(column);
+                matrix.values.push(value);
+            }
+            matrix.row_starts.push(matrix.columns.len());
+        }
+        matrix
+    }
+
+    /// Identity matrix of `size` states
+    fn identity(size: usize) -> Self {
+        Self::from_rows((0..size).map(|row| vec![(row, 1.)]))
+    }
+
+    /// Multiply row vector `input` by the matrix
+    pub(crate) fn apply(&self, input: &[f64]) -> Vec<f64> {
+        let mut output = vec![0.; self.size()];
+        for (row, &mass) in input.iter().enumerate() {
+            if mass == 0. {
+                continue;
+            }
+            for (column, value) in self.row(row) {
+                output[column] += mass * value;
+            }
+        }
+        output
+    }
+
+    /// Multiply the matrix by `other`
+    fn multiply(&self, other: &TransitionMatrix) -> TransitionMatrix {
+        let mut accumulator = vec![0.; other.size()];
+        let mut touched = Vec::new();
+        Self::from_rows((0..self.size()).map(|row| {
+            for (k, a) in self.row(row) {
+                for (column, b) in other.row(k) {
+                    if accumulator[column] == 0. {
+                        touched.push(column);
+                    }
+                    accumulator[column] += a * b;
+                }
+            }
+            touched.sort_unstable();
+            touched.dedup();
+            touched
+                .drain(..)
+                .filter_map(|column| {
+                    let value = std::mem::take(&mut accumulator[column]);
+                    (value != 0.).then_some((column, value))
+                })
+                .collect()
+        }))
+    }
+
+    /// Add `other` to the matrix
+    fn add(&self, other: &TransitionMatrix) -> TransitionMatrix {
+        Self::from_rows((0..self.size()).map(|row| {
+            let mut entries: Vec<(usize, f64)> = self.row(row).chain(other.row(row)).collect();
+            entries.sort_unstable_by_key(|&(column, _)| column);
+            entries.dedup_by(|(column, value), (kept_column, kept_value)| {
+                if column == kept_column {
+                    *kept_value += *value;
+                    true
+                } else {
+                    false
+                }
+            });
+            entries
+        }))
+    }
+}
+
+/// Range of moves with the same movement rules
+pub(crate) struct Phase {
+    /// first move of the phase
+    first_move: u32,
+    /// transitions between states
+    matrix: TransitionMatrix,
+    /// tells which matrix entries are jumps
+    jumps: Vec<bool>,
+    /// mass lost by movers in each state (with nowhere to go)
+    losses: Vec<f64>,
+}
+
+impl Phase {
+    /// Calculate movers after a single move, and the mass lost on the way
+    ///
+    /// Moves between cells are added to `flows` (8 per cell), if given.
+    pub(crate) fn advance(&self, movers: &[f64], flows: Option<&mut [f64]>) -> (Vec<f64>, f64) {
+        if let Some(flows) = flows {
+            self.add_flows(movers, flows);
+        }
+        (self.matrix.apply(movers), self.lost(movers))
+    }
+
+    /// Mass lost by `movers` on a single move
+    fn lost(&self, movers: &[f64]) -> f64 {
+        movers
+            .iter()
+            .zip(&self.losses)
+            .map(|(mass, loss)| mass * loss)
+            .sum()
+    }
+
+    /// Add moves of `movers` between cells to `flows` (8 per cell)
+    fn add_flows(&self, movers: &[f64], flows: &mut [f64]) {
+        for (state, &mass) in movers.iter().enumerate() {
+            if mass == 0. {
+                continue;
+            }
+            let matrix = &self.matrix;
+            for entry in matrix.row_starts[state]..matrix.row_starts[state + 1] {
+                let slot = if self.jumps[entry] { 4 } else { 0 } + matrix.columns[entry] % 4;
+                flows[state / 4 * 8 + slot] += mass * matrix.values[entry];
+            }
+        }
+    }
+}
+
+/// Transitions of every phase of the move count dynamics
+///
+/// Movement rules change only at a few moves (see `MovementModel`),
+/// so a matrix is kept for every range of moves with the same rules.
+#[derive(Default)]
+pub(crate) struct CompiledTransitions {
+    /// phases in order of moves
+    phases: Vec<Phase>,
+}
+
+impl CompiledTransitions {
+    /// Get the phase of move `move_count`
+    pub(crate) fn get(&self, move_count: u32) -> Option<&Phase> {
+        self.phases
+            .iter()
+            .rev()
+            .find(|phase| move_count >= phase.first_move)
+    }
+}
+
+impl Subway {
+    /// Precompile transitions of the current field, rules and modifiers
+    pub(crate) fn compile_transitions(&mut self) {
+        let mut first_moves = vec![
+            1,
+            self.model.jump_start_move().max(1),
+            self.model.entrance_exit_move().max(1),
+        ];
+        first_moves.sort_unstable();
+        first_moves.dedup();
+
+        let phases = first_moves
+            .into_iter()
+            .map(|first_move| {
+                let (matrix, jumps) = self.transition_matrix(first_move);
+                let losses = self.state_losses(&matrix, first_move);
+                Phase {
+                    first_move,
+                    matrix,
+                    jumps,
+                    losses,
+                }
+            })
+            .collect();
+        self.transitions = Rc::new(CompiledTransitions { phases });
+    }
+
+    /// Forget precompiled transitions, e.g. when the field changes
+    pub(crate) fn drop_transitions(&mut self) {
+        self.transitions = Rc::default();
+    }
+
+    /// Build transitions of move `move_count`, telling which of them are jumps
+    fn transition_matrix(&self, move_count: u32) -> (TransitionMatrix, Vec<bool>) {
+        let mut jumps = Vec::new();
+        let matrix = TransitionMatrix::from_rows((0..self.field.len()).flat_map(|idx| {
+            DIRECTIONS.map(|d| {
+                if !self.is_inner(idx) {
+                    return Vec::new();
+                }
+                let mut row: Vec<(usize, f64, bool)> = self
+                    .mover_moves(idx, d, move_count)
+                    .map(|(dir, next_idx, next_dir, prob)| {
+                        (state_idx(next_idx, next_dir), prob, dir >= 4)
+                    })
+                    .collect();
+                row.sort_unstable_by_key(|&(column, _, _)| column);
+                jumps.extend(row.iter().map(|&(_, _, jump)| jump));
+                row.into_iter()
+                    .map(|(column, prob, _)| (column, prob))
+                    .collect()
+            })
+        }));
+        (matrix, jumps)
+    }
+
+    /// Mass lost by movers in each state on a move with `matrix` transitions
+    fn state_losses(&self, matrix: &TransitionMatrix, move_count: u32) -> Vec<f64> {
+        (0..matrix.size())
+            .map(|state| {
+                if self.is_exit(state / 4, move_count) {
+                    0.
+                } else {
+                    (1. - matrix.row(state).map(|(_, prob)| prob).sum::<f64>()).max(0.)
+                }
+            })
+            .collect()
+    }
+}
+
+#[wasm_bindgen]
+impl Subway {
+    /// Perform `steps` steps at once, continuing from the last performed step
+    ///
+    /// Once movement rules stop changing (see `MovementModel::stationary_move`),
+    /// the moves are done with binary powers of the transition matrix,
+    /// which is faster for long horizons. Arrivals and the mass balance
+    /// of these steps are combined and recorded for the last one.
+    /// Fields with mark effects, delayed groups or first visit tracking
+    /// are stepped one by one, with every step recorded.
+    pub fn jump_ahead(&mut self, steps: u32) {
+        let target = self.last_step + steps;
+        let squaring = !self.has_mark_effects()
+            && self.get_delayed_mass() == 0.
+            && self.first_visits.is_none();
+        while self.last_step < target
+            && (!squaring || self.last_step + 1 < self.model.stationary_move())
+        {
+            self.step(self.last_step + 1);
+        }
+        if self.last_step == target {
+            return;
+        }
+        if self.transitions.get(self.last_step + 1).is_none() {
+            self.compile_transitions();
+        }
+        let transitions = Rc::clone(&self.transitions);
+        let phase = match transitions.get(self.last_step + 1) {
+            Some(phase) => phase,
+            None => return,
+        };
+        let matrix = &phase.matrix;
+        let moves = (target - self.last_step) * self.modifiers.moves_per_step();
+
+        // binary powers of the matrix `power` and sums of lower powers `sum`
+        // give both movers after all moves and the sum of movers of every move
+        let mut movers = self.movers.as_slice().to_vec();
+        let mut passed = vec![0.; movers.len()];
+        let mut power = matrix.clone();
+        let mut sum = TransitionMatrix::identity(matrix.size());
+        let mut remaining = moves;
+        while remaining > 0 {
+            if remaining & 1 == 1 {
+                for (total, mass) in passed.iter_mut().zip(sum.apply(&movers)) {
+                    *total += mass;
+                }
+                movers = power.apply(&movers);
+            }
+            remaining >>= 1;
+            if remaining > 0 {
+                sum = sum.add(&sum.multiply(&power));
+                power = power.multiply(&power);
+            }
+        }
+
+        // ledger, arrivals and flows of the whole jump
+        let step_pos = target as usize - 1;
+        let mut entry = LedgerEntry {
+            step: target,
+            start: self.in_flight_mass(),
+            lost: phase.lost(&passed),
+            ..Default::default()
+        };
+        for (idx, series) in self.arrivals.iter_mut() {
+            if series.len() <= step_pos {
+                series.resize(step_pos + 1, 0.);
+            }
+            let arrived: f64 = passed[state_idx(*idx, 0)..state_idx(*idx + 1, 0)]
+                .iter()
+                .sum();
+            series[step_pos] = arrived;
+            if self.field[*idx] == Cell::Entrance {
+                entry.entrance += arrived;
+            } else {
+                entry.exits += arrived;
+            }
+        }
+        for (idx, visits) in self.visited.iter_mut().enumerate() {
+            *visits += passed[state_idx(idx, 0)..state_idx(idx + 1, 0)]
+                .iter()
+                .sum::<f64>();
+        }
+        phase.add_flows(&passed, self.flows.as_mut_slice());
+        self.movers.as_mut_slice().copy_from_slice(&movers);
+        self.finish_step(entry);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::effects::MarkEffect;
+    use crate::field::Mark;
+    use crate::movement::DungeonModifiers;
+    use wasm_bindgen_test::*;
+
+    #[wasm_bindgen_test]
+    fn test_compiled_steps() {
+        let modifiers = DungeonModifiers {
+            jumpy: true,
+            ..Default::default()
+        };
+        let mut compiled = Subway::loop_field();
+        compiled.init(&modifiers);
+        let mut direct = Subway::loop_field();
+        direct.init(&modifiers);
+        direct.drop_transitions();
+
+        for step_number in 1..=40 {
+            compiled.step(step_number);
+            direct.step(step_number);
+        }
+        for (a, b) in compiled.visited.iter().zip(direct.visited.iter()) {
+            assert!((a - b).abs() < 1e-12);
+        }
+        for (a, b) in compiled.movers.iter().zip(direct.movers.iter()) {
+            assert!((a - b).abs() < 1e-12);
+        }
+        for (a, b) in compiled.flows.iter().zip(direct.flows.iter()) {
+            assert!((a - b).abs() < 1e-12);
+        }
+    }
+
+    #[wasm_bindgen_test]
+    fn test_jump_ahead() {
+        for double_speed in [false, true] {
+            let modifiers = DungeonModifiers {
+                jumpy: true,
+                double_speed,
+                ..Default::default()
+            };
+            let mut stepped = Subway::loop_field();
+            stepped.init(&modifiers);
+            for step_number in 1..=77 {
+                stepped.step(step_number);
+            }
+            let mut jumped = Subway::loop_field();
+            jumped.init(&modifiers);
+            jumped.jump_ahead(77);
+
+            assert_eq!(jumped.get_last_step(), 77);
+            for (a, b) in jumped.visited.iter().zip(stepped.visited.iter()) {
+                assert!((a - b).abs() < 1e-9, "{} != {}", a, b);
+            }
+            for (a, b) in jumped.movers.iter().zip(stepped.movers.iter()) {
+                assert!((a - b).abs() < 1e-9, "{} != {}", a, b);
+            }
+            for (a, b) in jumped.flows.iter().zip(stepped.flows.iter()) {
+                assert!((a - b).abs() < 1e-9, "{} != {}", a, b);
+            }
+            let total = |subway: &Subway| subway.get_arrivals(127).iter().sum::<f64>();
+            assert!((total(&jumped) - total(&stepped)).abs() < 1e-9);
+            let ledger = jumped.get_ledger();
+            assert!(ledger.iter().all(|entry| entry.is_balanced()));
+            // steps before the rules settle are recorded one by one, the rest at once
+            let steps: Vec<u32> = ledger[..19].iter().map(|entry| entry.step).collect();
+            assert_eq!(steps, (1..=19).collect::<Vec<u32>>());
+            assert_eq!(ledger[76].step, 77);
+            let exits = |subway: &Subway| subway.get_ledger().iter().map(|e| e.exits).sum::<f64>();
+            assert!((exits(&jumped) - exits(&stepped)).abs() < 1e-9);
+        }
+
+        // with mark effects, every step is performed and recorded
+        let mut jumped = Subway::loop_field();
+        jumped.set_mark(88, Mark::FinalBoss);
+        jumped.set_mark_effect(Mark::FinalBoss, MarkEffect::absorb(0.5));
+        jumped.init(&DungeonModifiers::default());
+        jumped.jump_ahead(30);
+        let steps: Vec<u32> = jumped.get_ledger().iter().map(|entry| entry.step).collect();
+        assert_eq!(steps, (1..=30).collect::<Vec<u32>>());
+        assert!(jumped.get_stopped_mass() > 0.);
+    }
+}
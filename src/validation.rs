@@ -0,0 +1,195 @@
+use wasm_bindgen::prelude::*;
+
+use crate::field::{Cell, Mark, Subway};
+
+/// Kind of a problem with the field
+#[wasm_bindgen]
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub enum ProblemKind {
+    /// there is no entrance, so no group ever starts
+    NoEntrance = 0,
+    /// there are several entrances
+    MultipleEntrances = 1,
+    /// there are no exits
+    NoExit = 2,
+    /// an exit cannot be reached from the entrance
+    ExitUnreachable = 3,
+    /// passable cells cannot be reached from the entrance
+    IsolatedRegion = 4,
+    /// marks are drawn on walls, where groups never meet them
+    MarkOnWall = 5,
+}
+
+/// Problem with the field that makes the results meaningless
+#[wasm_bindgen]
+#[derive(Clone)]
+pub struct FieldProblem {
+    pub kind: ProblemKind,
+    cells: Vec<usize>,
+}
+
+#[wasm_bindgen]
+impl FieldProblem {
+    /// Get cells the problem is about (none for missing cells)
+    pub fn get_cells(&self) -> Vec<usize> {
+        self.cells.clone()
+    }
+}
+
+#[wasm_bindgen]
+impl Subway {
+    /// Check if the field makes sense, listing all problems found
+    ///
+    /// Reachability takes jumps into account in jumpy dungeons
+    /// (as set by the last `init`).
+    pub fn validate(&self) -> Vec<FieldProblem> {
+        let mut problems = Vec::new();
+        let cells_of = |cell: Cell| -> Vec<usize> {
+            (0..self.field.len())
+                .filter(|&idx| self.field[idx] == cell)
+                .collect()
+        };
+        let entrances = cells_of(Cell::Entrance);
+        let exits = cells_of(Cell::Exit);
+
+        match entrances.len() {
+            0 => problems.push(FieldProblem {
+                kind: ProblemKind::NoEntrance,
+                cells: Vec::new(),
+            }),
+            1 => (),
+            _ => problems.push(FieldProblem {
+                kind: ProblemKind::MultipleEntrances,
+                cells: entrances.clone(),
+            }),
+        }
+        if exits.is_empty() {
+            problems.push(FieldProblem {
+                kind: ProblemKind::NoExit,
+                cells: Vec::new(),
+            });
+        }
+        let marked_walls: Vec<usize> = cells_of(Cell::Wall)
+            .into_iter()
+            .filter(|&idx| !matches!(self.marks[idx], Mark::None | Mark::Wall))
+            .collect();
+        if !marked_walls.is_empty() {
+            problems.push(FieldProblem {
+                kind: ProblemKind::MarkOnWall,
+                cells: marked_walls,
+            });
+        }
+        if entrances.is_empty() {
+            // nothing is reachable without an entrance
+            return problems;
+        }
+
+        let jumps = self.modifiers.jumpy;
+        let mut reached: Vec<bool> = self
+            .distances_from(&entrances, jumps)
+            .iter()
+            .map(Option::is_some)
+            .collect();
+        for &exit in &exits {
+            if !reached[exit] {
+                problems.push(FieldProblem {
+                    kind: ProblemKind::ExitUnreachable,
+                    cells: vec![exit],
+                });
+                // reported once, not again as a part of an isolated region
+                reached[exit] = true;
+            }
+        }
+        for idx in 0..self.field.len() {
+            if reached[idx] || self.field[idx] != Cell::Pass {
+                continue;
+            }
+            let region: Vec<usize> = self
+                .distances_from(&[idx], jumps)
+                .iter()
+                .enumerate()
+                .filter_map(|(idx, distance)| distance.map(|_| idx))
+                .filter(|&idx| !reached[idx])
+                .collect();
+            for &cell in &region {
+                reached[cell] = true;
+            }
+            problems.push(FieldProblem {
+                kind: ProblemKind::IsolatedRegion,
+                cells: region,
+            });
+        }
+        problems
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::movement::DungeonModifiers;
+    use wasm_bindgen_test::*;
+
+    fn kinds(subway: &Subway) -> Vec<ProblemKind> {
+        subway
+            .validate()
+            .iter()
+            .map(|problem| problem.kind)
+            .collect()
+    }
+
+    #[wasm_bindgen_test]
+    fn test_validate() {
+        let mut subway = Subway::new();
+        assert!(kinds(&subway) == vec![ProblemKind::NoEntrance, ProblemKind::NoExit]);
+
+        assert!(subway.set_field(128, Cell::Entrance));
+        assert!(subway.set_field(127, Cell::Pass));
+        assert!(subway.set_field(126, Cell::Exit));
+        assert!(subway.validate().is_empty());
+        // guard rails
+        assert!(!subway.set_field(19, Cell::Pass));
+        assert!(!subway.set_field(400, Cell::Pass));
+
+        // exit behind a wall, and a closed room
+        subway.set_field(124, Cell::Exit);
+        subway.set_field(122, Cell::Pass);
+        subway.set_field(102, Cell::Pass);
+        let problems = subway.validate();
+        assert_eq!(problems.len(), 2);
+        assert!(problems[0].kind == ProblemKind::ExitUnreachable);
+        assert_eq!(problems[0].get_cells(), vec![124]);
+        assert!(problems[1].kind == ProblemKind::IsolatedRegion);
+        assert_eq!(problems[1].get_cells(), vec![102, 122]);
+
+        // jumps only start after groups have left through the first exit,
+        // but the closed room is left by jumping
+        subway.init(&DungeonModifiers {
+            jumpy: true,
+            ..Default::default()
+        });
+        subway.set_field(125, Cell::Pass);
+        let problems = subway.validate();
+        assert!(
+            kinds(&subway)
+                == vec![
+                    ProblemKind::ExitUnreachable,
+                    ProblemKind::IsolatedRegion,
+                    ProblemKind::IsolatedRegion
+                ]
+        );
+        assert_eq!(problems[0].get_cells(), vec![124]);
+        assert_eq!(problems[1].get_cells(), vec![102, 122]);
+        assert_eq!(problems[2].get_cells(), vec![125]);
+
+        subway.set_field(130, Cell::Entrance);
+        assert!(kinds(&subway)[0] == ProblemKind::MultipleEntrances);
+
+        // marks on walls, wall marks aside
+        subway.set_field(130, Cell::Wall);
+        assert!(subway.set_mark(129, Mark::Trap));
+        assert!(subway.set_mark(131, Mark::Wall));
+        let problems = subway.validate();
+        assert!(problems[0].kind == ProblemKind::MarkOnWall);
+        assert_eq!(problems[0].get_cells(), vec![129]);
+    }
+}
@@ -1,7 +1,7 @@
 /// Global state and methods
 
-import { Subway, Cell, Mark } from "gv_subway"
-import { reactive } from "vue"
+import { Subway, Cell, Mark, DungeonModifiers, SubwaySnapshot, ExitKind } from "gv_subway"
+import { markRaw, reactive } from "vue"
 
 export const MarkSymbols = new Map<Mark, string>([
     [Mark.None, ' '],
@@ -18,28 +18,54 @@ export const MarkSymbols = new Map<Mark, string>([
     [Mark.Scarecrow, '👻']
 ])
 
+/// Steps between saved states, the steps in between are replayed
+const SNAPSHOT_INTERVAL = 10
+
 export const stField = reactive({
     field: new Subway(),
     cells: (new Array(400)).fill(null).map(_ => ({ cellType: Cell.Wall, prob: 0 })),
     marks: (new Array(400)).fill(null).map(_ => Mark.None),
     outer: (new Array(400)).fill(null).map(_ => false),
     isJumpy: false,
-    earlyEntranceVisitors: 0., // fraction visited entrance before 20th step
-
-    recalculate(numSteps: number, updateFrom: number = 0) {
-        if (!updateFrom) this.field.init(this.isJumpy);
-        for (let i = 1; i <= numSteps; i++) {
-            this.field.step(i + updateFrom);
-            if (i + updateFrom <= 19) {
-                this.earlyEntranceVisitors = this.marks.reduce((acc, m, idx) => {
-                    return acc + (m == Mark.Entrance ? this.field.get_visited_probability(idx) : 0.)
-                }, 0.)
-                // After 20th step everyone visiting the entrance cell exits,
-                // but we still get the total count of visitors.
-                // So we track how many have visited the cell before it became
-                // an exit cell and calculate the fraction of exits
-            }
+    isDoubleSpeed: false,
+    isReversed: false,
+    entranceExitProb: 0., // fraction left through the entrance
+    snapshots: markRaw(new Map<number, SubwaySnapshot>()), // saved states by step number, every SNAPSHOT_INTERVAL steps
+
+    /// Initialize the field with the selected modifiers
+    initField() {
+        const modifiers = new DungeonModifiers()
+        modifiers.jumpy = this.isJumpy
+        modifiers.double_speed = this.isDoubleSpeed
+        modifiers.reversed = this.isReversed
+        this.field.init(modifiers)
+        // wasm memory is not garbage collected
+        modifiers.free()
+    },
+
+    dropSnapshots() {
+        this.snapshots.forEach(snapshot => snapshot.free())
+        this.snapshots.clear()
+    },
+
+    recalculate(numSteps: number, keepSnapshots: boolean = false) {
+        if (!keepSnapshots) this.dropSnapshots();
+        // continue from the closest saved state
+        let from = 0;
+        this.snapshots.forEach((_, step) => {
+            if (step <= numSteps && step > from) from = step
+        })
+        if (from > 0)
+            this.field.restore(this.snapshots.get(from)!);
+        else
+            this.initField();
+        for (let i = from + 1; i <= numSteps; i++) {
+            this.field.step(i);
+            if (i % SNAPSHOT_INTERVAL == 0)
+                this.snapshots.set(i, this.field.snapshot());
         }
+        // entrance visitors only leave after it becomes an exit
+        this.entranceExitProb = this.field.get_entrance_exit_probability();
         // update field probabilities
         for (let cell_id = 0; cell_id < 400; cell_id++) {
             if (this.cells[cell_id].cellType != Cell.Wall)
@@ -47,8 +73,10 @@ export const stField = reactive({
                     this.field.get_visited_probability(cell_id);
         }
     },
-    reset() {
-        this.field.init(this.isJumpy);
+    reset(keepSnapshots: boolean = false) {
+        if (!keepSnapshots) this.dropSnapshots();
+        this.initField();
+        this.entranceExitProb = 0;
         for (let cell_id = 0; cell_id < 400; cell_id++) {
             this.cells[cell_id].prob = 0;
         }
@@ -56,34 +84,44 @@ export const stField = reactive({
 
     /// Set new cell type with automatic mark
     setCell(cellIdx: number, cellType: Cell, mark?: Mark) {
-        this.field.set_field(cellIdx, cellType)
+        if (cellType == Cell.Exit)
+            this.field.set_exit(cellIdx, mark == Mark.Subtreasury ? ExitKind.Subtreasury
+                : mark == Mark.Treasury || mark === undefined ? ExitKind.Treasury : ExitKind.Extra)
+        else
+            this.field.set_field(cellIdx, cellType)
         this.cells[cellIdx].cellType = this.field.get_field(cellIdx)
         if (this.cells[cellIdx].cellType != cellType)
             // update field failed
             return;
         if (cellType == Cell.Entrance)
-            this.marks[cellIdx] = Mark.Entrance
+            this.setMark(cellIdx, Mark.Entrance)
         else if (cellType == Cell.Exit)
-            this.marks[cellIdx] = mark ?? Mark.Treasury
+            this.setMark(cellIdx, mark ?? Mark.Treasury)
         else if (cellType == Cell.Pass) {
             // for space: keep marks, unless it is a specialized mark
             if (mark !== undefined)
-                this.marks[cellIdx] = mark
+                this.setMark(cellIdx, mark)
             else if ([Mark.RaiseWall, Mark.Entrance, Mark.Treasury, Mark.Subtreasury].indexOf(this.marks[cellIdx]) >= 0)
-                this.marks[cellIdx] = Mark.None
+                this.setMark(cellIdx, Mark.None)
         }
         else
-            this.marks[cellIdx] = mark ?? Mark.None
+            this.setMark(cellIdx, mark ?? Mark.None)
         
         this.outerSweep()
     },
 
+    /// Set mark of a cell, keeping the displayed marks in sync with the field
+    setMark(cellIdx: number, mark: Mark) {
+        this.field.set_mark(cellIdx, mark)
+        this.marks[cellIdx] = this.field.get_mark(cellIdx)
+    },
+
     clearByType(cellType: Cell, clearType: Cell = Cell.Pass) {
         // clear all cells with same type
         this.cells.forEach((c, idx) => {
             if (c.cellType == cellType) {
                 this.setCell(idx, clearType)
-                this.marks[idx] = Mark.None
+                this.setMark(idx, Mark.None)
             }
         })
     },
@@ -93,7 +131,7 @@ export const stField = reactive({
         this.cells.forEach((c, idx) => {
             if (c.cellType == cellType && this.marks[idx] == mark) {
                 this.setCell(idx, clearType)
-                this.marks[idx] = Mark.None
+                this.setMark(idx, Mark.None)
             }
         })
     },
@@ -143,8 +181,9 @@ export function encodeMap(): string {
 
         result += s_row;
     }
-    let specials_string = "etb".split("").reduce((acc, c, idx) => {
-        const p = stField.marks.indexOf([Mark.Entrance, Mark.Treasury, Mark.FinalBoss][idx]);
+    let specials_string = "etbs".split("").reduce((acc, c, idx) => {
+        const p = stField.marks.indexOf(
+            [Mark.Entrance, Mark.Treasury, Mark.FinalBoss, Mark.Subtreasury][idx]);
         if (p == -1) return acc;
         return acc + `&${c}=${p}`;
     }, "");
@@ -186,16 +225,19 @@ export function parseMap(request: string) {
             Mark.Entrance,
             Mark.Treasury,
             Mark.FinalBoss,
+            Mark.Subtreasury,
         ].forEach((mark, idx) => {
             const fieldIdx = parseInt(
-                fields.get("etb".charAt(idx)) || "-1"
+                fields.get("etbs".charAt(idx)) || "-1"
             );
             if (fieldIdx >= 0) {
-                stField.marks[fieldIdx] = mark
+                stField.setMark(fieldIdx, mark)
                 if (mark == Mark.Entrance)
                     stField.field.set_field(fieldIdx, Cell.Entrance);
                 else if (mark == Mark.Treasury)
-                    stField.field.set_field(fieldIdx, Cell.Exit);
+                    stField.field.set_exit(fieldIdx, ExitKind.Treasury);
+                else if (mark == Mark.Subtreasury)
+                    stField.field.set_exit(fieldIdx, ExitKind.Subtreasury);
             }
         });
         // apply field